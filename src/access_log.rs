@@ -0,0 +1,257 @@
+use crate::body::Body;
+use crate::config::{AccessLogConfig, LogFormat};
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Mutex, OnceLock};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+use http_body_util::BodyExt;
+use hyper::body::{Bytes, Frame, SizeHint};
+use hyper::{Request, Response};
+
+type Resp = Response<Body>;
+type Req = Request<hyper::body::Incoming>;
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+struct Logger {
+    format: LogFormat,
+    path: Option<PathBuf>,
+    file: Mutex<Option<File>>,
+}
+
+/// Everything we know about a request before it's handled.
+pub struct Entry {
+    remote_addr: SocketAddr,
+    time: DateTime<Utc>,
+    started: Instant,
+    method: String,
+    target: String,
+    version: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+/// Wraps a response body to count the bytes that were actually sent. The
+/// request is logged once the body is done (or dropped early).
+struct LoggedBody {
+    inner: Body,
+    entry: Entry,
+    status: u16,
+    bytes_sent: u64,
+}
+
+pub fn init(config: &AccessLogConfig) -> Result<(), String> {
+    if !config.enabled {
+        return Ok(());
+    }
+
+    let file = match &config.path {
+        Some(path) => Some(open_log_file(path)?),
+        None => None,
+    };
+
+    let logger = Logger {
+        format: config.format,
+        path: config.path.clone(),
+        file: Mutex::new(file),
+    };
+
+    if LOGGER.set(logger).is_err() {
+        return Err(String::from("Access log should only be initialized once!"));
+    }
+
+    Ok(())
+}
+
+/// Reopens the log file on `SIGHUP`, so logrotate can move the old one away.
+pub fn reopen_on_sighup() -> Result<(), String> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())
+        .map_err(|error| format!("Couldn't listen for SIGHUP: {error}"))?;
+
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            if let Some(logger) = LOGGER.get() {
+                logger.reopen();
+            }
+        }
+    });
+
+    Ok(())
+}
+
+impl Entry {
+    pub fn new(request: &Req, remote_addr: SocketAddr) -> Entry {
+        let target = request
+            .uri()
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or("/");
+
+        Entry {
+            remote_addr,
+            time: Utc::now(),
+            started: Instant::now(),
+            method: request.method().to_string(),
+            target: target.to_string(),
+            version: format!("{:?}", request.version()),
+            referer: header_string(request, "Referer"),
+            user_agent: header_string(request, "User-Agent"),
+        }
+    }
+}
+
+/// Makes sure the request gets logged once the response has been sent.
+pub fn attach(response: Resp, entry: Entry) -> Resp {
+    if LOGGER.get().is_none() {
+        return response;
+    }
+
+    let status = response.status().as_u16();
+
+    response.map(|inner| {
+        LoggedBody {
+            inner,
+            entry,
+            status,
+            bytes_sent: 0,
+        }
+        .boxed_unsync()
+    })
+}
+
+impl hyper::body::Body for LoggedBody {
+    type Data = Bytes;
+    type Error = std::io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            if let Some(data) = frame.data_ref() {
+                self.bytes_sent += data.len() as u64;
+            }
+        }
+
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        if let Some(logger) = LOGGER.get() {
+            logger.write(&format_line(logger.format, &self.entry, self.status, self.bytes_sent));
+        }
+    }
+}
+
+impl Logger {
+    fn write(&self, line: &str) {
+        let mut file = self.file.lock().unwrap();
+
+        let result = match file.as_mut() {
+            Some(file) => file.write_all(line.as_bytes()),
+            None => std::io::stdout().lock().write_all(line.as_bytes()),
+        };
+
+        if let Err(error) = result {
+            eprintln!("Couldn't write to the access log: {error}");
+        }
+    }
+
+    fn reopen(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+
+        match open_log_file(path) {
+            Ok(file) => *self.file.lock().unwrap() = Some(file),
+            Err(error) => eprintln!("{error}"),
+        }
+    }
+}
+
+fn open_log_file(path: &Path) -> Result<File, String> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|error| format!("Couldn't open access log {}: {error}", path.display()))
+}
+
+fn format_line(format: LogFormat, entry: &Entry, status: u16, bytes_sent: u64) -> String {
+    match format {
+        LogFormat::Common => format!("{}\n", common_log_line(entry, status, bytes_sent)),
+        LogFormat::Combined => format!(
+            "{} \"{}\" \"{}\"\n",
+            common_log_line(entry, status, bytes_sent),
+            escape(entry.referer.as_deref().unwrap_or("-")),
+            escape(entry.user_agent.as_deref().unwrap_or("-")),
+        ),
+        LogFormat::Json => {
+            let line = serde_json::json!({
+                "time": entry.time.to_rfc3339(),
+                "remote_addr": entry.remote_addr.ip().to_string(),
+                "method": entry.method,
+                "path": entry.target,
+                "protocol": entry.version,
+                "status": status,
+                "bytes_sent": bytes_sent,
+                "duration_ms": entry.started.elapsed().as_secs_f64() * 1000.0,
+                "referer": entry.referer,
+                "user_agent": entry.user_agent,
+            });
+
+            format!("{line}\n")
+        }
+    }
+}
+
+/// `host ident authuser [date] "request" status bytes`
+fn common_log_line(entry: &Entry, status: u16, bytes_sent: u64) -> String {
+    let bytes_sent = match bytes_sent {
+        0 => String::from("-"),
+        bytes_sent => bytes_sent.to_string(),
+    };
+
+    format!(
+        "{} - - [{}] \"{} {} {}\" {status} {bytes_sent}",
+        entry.remote_addr.ip(),
+        entry.time.format("%d/%b/%Y:%H:%M:%S %z"),
+        escape(&entry.method),
+        escape(&entry.target),
+        entry.version,
+    )
+}
+
+/// Keeps client-provided values from breaking out of their quotes.
+fn escape(value: &str) -> String {
+    value.escape_default().to_string()
+}
+
+fn header_string(request: &Req, name: &str) -> Option<String> {
+    request
+        .headers()
+        .get(name)
+        .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+}
@@ -1,95 +1,179 @@
+use crate::body::Body;
 use crate::config;
+use crate::credentials;
+use crate::lockout;
+use crate::permissions;
 use crate::response;
+use crate::tokens::{self, Token};
+
+use std::net::IpAddr;
+use std::sync::Arc;
 
-use http_body_util::Full;
-use hyper::body::Bytes;
 use hyper::{Request, Response};
 use hyper::header::HeaderValue;
 
-type Resp = Response<Full<Bytes>>;
+type Resp = Response<Body>;
 type Req = Request<hyper::body::Incoming>;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct ClientCredentials {
-    pub username: String,
-    pub password: String,
+pub enum ClientCredentials {
+    Basic { username: String, password: String },
+    Bearer { token: String },
+}
+
+/// Who made the request: a user from the credentials file, or an API token.
+pub enum Identity {
+    User(String),
+    Token(Arc<Token>),
 }
 
-pub fn require_authentication(request: Req) -> Result<Req, Resp> {
-    let auth = match fetch_auth_credentials(&request) {
+/// Authenticates the user, and then checks whether they're allowed to
+/// use this method on this path. Tokens are limited to their own scope
+/// instead of the permission rules.
+#[allow(clippy::result_large_err)]
+pub async fn require_permission(request: Req, remote_ip: IpAddr) -> Result<Req, Resp> {
+    let (request, identity) = require_authentication(request, remote_ip).await?;
+    let (method, path) = (request.method(), request.uri().path());
+
+    let authorized = match &identity {
+        Identity::User(username) => permissions::authorize(username, method, path),
+        Identity::Token(token) => token.authorize(method, path),
+    };
+
+    match authorized {
+        Ok(()) => Ok(request),
+        Err(denial) => Err(response::serve(403, &denial)),
+    }
+}
+
+/// Returns the request together with whoever was authenticated. Addresses
+/// with too many failed logins are locked out for a while.
+#[allow(clippy::result_large_err)]
+pub async fn require_authentication(request: Req, remote_ip: IpAddr) -> Result<(Req, Identity), Resp> {
+    if !config::get().auth.has_credentials() {
+        return Err(response::serve(
+            403,
+            "Forbidden; publishing is disabled, since no credentials are configured.",
+        ));
+    }
+
+    let credentials = match fetch_auth_credentials(&request) {
         Some(credentials) => credentials,
         None => return Err(serve_auth_required()),
     };
 
-    if auth.username == config::AUTH_USERNAME && auth.password == config::AUTH_PASSWORD {
-        Ok(request)
+    if let Err(retry_after) = lockout::check(remote_ip) {
+        return Err(response::serve_429("Too many failed logins; try again later.", retry_after));
+    }
+
+    let (username, password) = match credentials {
+        ClientCredentials::Basic { username, password } => (username, password),
+        ClientCredentials::Bearer { token } => {
+            return match tokens::authenticate(&token) {
+                Ok(token) => {
+                    lockout::record_success(remote_ip);
+                    Ok((request, Identity::Token(token)))
+                }
+                Err(reason) => {
+                    lockout::record_failure(remote_ip, "a bearer token");
+                    Err(serve_invalid_token(&reason))
+                }
+            };
+        }
+    };
+
+    // Password hashes are slow to verify on purpose, so keep that
+    // off the async runtime.
+    let user = username.clone();
+    let valid = tokio::task::spawn_blocking(move || {
+        credentials::verify(&config::get().auth, &user, &password)
+    })
+    .await
+    .unwrap_or(false);
+
+    if valid {
+        lockout::record_success(remote_ip);
+        Ok((request, Identity::User(username)))
     } else {
+        lockout::record_failure(remote_ip, &format!("user `{}`", username.escape_default()));
         Err(response::serve(
             403,
-            "Invalid credentials; authentication is required for `PUT` requests.",
+            "Invalid credentials; authentication is required for `PUT` and `DELETE` requests.",
         ))
     }
 }
 
 fn fetch_auth_credentials(request: &Req) -> Option<ClientCredentials> {
-    let header = match request.headers().get("Authorization") {
-        None => return None,
-        Some(header) => header.to_str().unwrap(),
-    };
+    let header = request.headers().get("Authorization")?.to_str().ok()?;
 
-    let mut split = header.splitn(2, |c| c == ' ');
-    let authtype = match split.next() {
-        None => return None,
-        Some(t) => t,
-    };
+    let mut split = header.splitn(2, ' ');
+    let authtype = split.next()?;
+
+    if authtype.eq_ignore_ascii_case("Bearer") {
+        let token = split.next()?.trim();
+
+        if token.is_empty() {
+            return None;
+        }
+
+        return Some(ClientCredentials::Bearer { token: token.to_string() });
+    }
 
     if authtype != "Basic" {
         return None;
     }
 
-    let authvalue = match split.next().and_then(|val| base64::decode(val).ok()) {
-        Some(v) => v,
-        None => return None,
-    };
+    let authvalue = split.next().and_then(|val| base64::decode(val).ok())?;
 
     let mut split = authvalue.splitn(2, |&c| c == b':');
 
-    let username = match split
+    let username = split
         .next()
         .map(Vec::from)
-        .and_then(|l| String::from_utf8(l).ok())
-    {
-        Some(l) => l,
-        None => return None,
-    };
-    let password = match split
+        .and_then(|l| String::from_utf8(l).ok())?;
+    let password = split
         .next()
         .map(Vec::from)
-        .and_then(|p| String::from_utf8(p).ok())
-    {
-        Some(p) => p,
-        None => return None,
-    };
+        .and_then(|p| String::from_utf8(p).ok())?;
 
-    Some(ClientCredentials { username, password })
+    Some(ClientCredentials::Basic { username, password })
 }
 
 fn serve_auth_required() -> Resp {
     let mut response = response::serve(
         401,
-        "Missing credentials; authentication is required for `PUT` requests.",
+        "Missing credentials; authentication is required for `PUT` and `DELETE` requests.",
     );
 
-    set_www_authenticate_header(&mut response);
+    set_www_authenticate_header(&mut response, None);
     response
 }
 
-fn set_www_authenticate_header(response: &mut Resp) {
+/// Invalid or expired tokens get a 401 (rather than a 403), as RFC 6750
+/// asks, so clients know to get a new token.
+fn serve_invalid_token(reason: &str) -> Resp {
+    let mut response = response::serve(401, reason);
+
+    set_www_authenticate_header(&mut response, Some("invalid_token"));
+    response
+}
+
+/// Advertises Basic, and Bearer too if there's a token store.
+fn set_www_authenticate_header(response: &mut Resp, bearer_error: Option<&str>) {
     let headers = response.headers_mut();
-    let header_value = format!("Basic realm=\"{}\"", config::AUTH_REALM);
+    let realm = &config::get().auth.realm;
 
-    headers.insert(
-        "WWW-Authenticate",
-        HeaderValue::from_str(&header_value).unwrap(),
-    );
+    let basic = format!("Basic realm=\"{realm}\"");
+    headers.append("WWW-Authenticate", HeaderValue::from_str(&basic).unwrap());
+
+    if !tokens::enabled() {
+        return;
+    }
+
+    let bearer = match bearer_error {
+        Some(error) => format!("Bearer realm=\"{realm}\", error=\"{error}\""),
+        None => format!("Bearer realm=\"{realm}\""),
+    };
+
+    headers.append("WWW-Authenticate", HeaderValue::from_str(&bearer).unwrap());
 }
@@ -0,0 +1,35 @@
+use std::io;
+
+use futures_util::{Stream, TryStreamExt};
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::{BodyDataStream, BodyExt, Empty, Full, StreamBody};
+use hyper::body::{Bytes, Frame};
+use tokio::io::{AsyncBufRead, AsyncRead};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+/// Response body used throughout waiter. Files are streamed from disk in
+/// chunks, while small messages are sent in one go.
+pub type Body = UnsyncBoxBody<Bytes, io::Error>;
+
+pub fn full(bytes: impl Into<Bytes>) -> Body {
+    Full::new(bytes.into())
+        .map_err(|never| match never {})
+        .boxed_unsync()
+}
+
+pub fn empty() -> Body {
+    Empty::new().map_err(|never| match never {}).boxed_unsync()
+}
+
+pub fn from_reader(reader: impl AsyncRead + Send + 'static) -> Body {
+    from_stream(ReaderStream::new(reader))
+}
+
+pub fn from_stream(stream: impl Stream<Item = io::Result<Bytes>> + Send + 'static) -> Body {
+    StreamBody::new(stream.map_ok(Frame::data)).boxed_unsync()
+}
+
+/// Turns the body back into something we can read from, eg. to compress it.
+pub fn into_reader(body: Body) -> impl AsyncBufRead + Send {
+    StreamReader::new(BodyDataStream::new(body))
+}
@@ -1,16 +1,630 @@
-pub const SERVER_NAME: &str = "dupunkto/waiter (Rust)";
-pub const CONTENT_LANGUAGE: &str = "en-US";
-pub const CONTENT_CHARSET: &str = "UTF-8";
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
-/// Cache time for static assets (in seconds)
-pub const CACHE_TIME_ASSETS: u64 = 31536000;
+use hyper::header::HeaderValue;
+use hyper::Method;
+use serde::Deserialize;
 
-/// Cache time for content (in seconds)
-pub const CACHE_TIME_CONTENT: u64 = 43200;
+static CONFIG: OnceLock<Config> = OnceLock::new();
 
-/// Directory to serve
-pub const SCOPE: &str = ".";
+/// About one request every quarter of an hour; anything but 0 below this
+/// is almost certainly a mistake.
+const MIN_REQUESTS_PER_SECOND: f64 = 0.001;
 
-pub const AUTH_REALM: &str = "PUT";
-pub const AUTH_USERNAME: &str = "root";
-pub const AUTH_PASSWORD: &str = "toor";
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address for the server to run on
+    pub address: String,
+
+    /// Directory to serve
+    pub scope: String,
+
+    /// Disable cache control and content encoding
+    pub dev: bool,
+
+    /// Time to let open connections finish when shutting down (in seconds)
+    pub shutdown_timeout: u64,
+
+    /// TOML file with redirect and rewrite rules, reloaded when it changes
+    pub redirects_file: Option<PathBuf>,
+
+    pub server_name: String,
+    pub content_language: String,
+    pub content_charset: String,
+
+    pub cache: CacheConfig,
+    pub compression: CompressionConfig,
+    pub auth: AuthConfig,
+    pub uploads: UploadsConfig,
+
+    /// Serve HTTPS as well; disabled if missing
+    pub tls: Option<TlsConfig>,
+
+    pub http2: Http2Config,
+    pub access_log: AccessLogConfig,
+    pub permissions: PermissionsConfig,
+    pub limits: LimitsConfig,
+    pub autoindex: AutoindexConfig,
+    pub fancy_urls: FancyUrlsConfig,
+    pub render: RenderConfig,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Cache time for static assets (in seconds)
+    pub assets: u64,
+
+    /// Cache time for content (in seconds)
+    pub content: u64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    /// Responses smaller than this (in bytes) aren't worth compressing
+    pub min_size: usize,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct FancyUrlsConfig {
+    /// Extensions tried in order when a path doesn't exist as is, so
+    /// `/page` can serve `page.htmd`
+    pub extensions: Vec<String>,
+
+    /// Names tried in order for the index of a directory, each with the
+    /// extensions above
+    pub indexes: Vec<String>,
+
+    /// Redirect `/page.html` to `/page`, and `/dir` to `/dir/`
+    pub canonical_redirects: bool,
+
+    /// Answer `406 Not Acceptable` if the client accepts none of the
+    /// variants of a page, instead of serving the first one anyway
+    pub strict_negotiation: bool,
+
+    /// Different extensions or indexes for some directories; the first
+    /// one that matches the path applies
+    pub directories: Vec<DirectoryFallbackConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DirectoryFallbackConfig {
+    /// Path prefixes or globs, like in the permission rules
+    pub paths: Vec<String>,
+
+    /// Falls back to the global list if missing
+    pub extensions: Option<Vec<String>>,
+    pub indexes: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenderConfig {
+    /// Render `.htmd` to HTML for clients that don't accept `text/htmd`
+    pub enabled: bool,
+
+    /// Template wrapping rendered pages, looked up in the page's own
+    /// directory first and then in its parents
+    pub template_name: String,
+
+    /// Rendered pages kept in memory
+    pub cache_size: usize,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AutoindexConfig {
+    /// List the files in directories without an index
+    pub enabled: bool,
+
+    /// List files starting with a `.` too
+    pub show_hidden: bool,
+
+    /// Directories containing a file with this name are never listed
+    pub opt_out_file: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub realm: String,
+
+    /// Single user, used when there's no credentials file. Without
+    /// either (or tokens), `PUT` and `DELETE` are refused.
+    pub username: Option<String>,
+    pub password: Option<String>,
+
+    /// htpasswd file with bcrypt, argon2 or SHA-crypt hashes, reloaded
+    /// when it changes
+    pub credentials_file: Option<PathBuf>,
+
+    /// TOML file with API tokens for `Authorization: Bearer`, reloaded
+    /// when it changes
+    pub tokens_file: Option<PathBuf>,
+
+    pub lockout: LockoutConfig,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LockoutConfig {
+    /// Lock out addresses with too many failed logins
+    pub enabled: bool,
+
+    /// Failed logins from a single address before it's locked out
+    pub max_failures: u32,
+
+    /// First lockout (in seconds); doubles with every failure after that
+    pub lockout_time: u64,
+
+    /// Longest possible lockout (in seconds)
+    pub max_lockout_time: u64,
+
+    /// Failures are forgotten after this long without new ones (in seconds)
+    pub reset_after: u64,
+
+    /// Addresses tracked at once; the least recently seen are forgotten first
+    pub max_tracked_addresses: usize,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadsConfig {
+    /// Maximum size of a single upload (in bytes)
+    pub max_size: u64,
+
+    /// Remove directories that become empty after a `DELETE`
+    pub prune_empty_directories: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Address for the HTTPS server to run on
+    #[serde(default = "default_tls_address")]
+    pub address: String,
+
+    /// PEM-encoded certificate chain, reloaded when it changes
+    pub cert: PathBuf,
+
+    /// PEM-encoded private key, reloaded when it changes
+    pub key: PathBuf,
+
+    /// Redirect every plain HTTP request to HTTPS
+    #[serde(default)]
+    pub redirect_http: bool,
+
+    /// Seconds a client gets to complete the TLS handshake
+    #[serde(default = "default_handshake_timeout")]
+    pub handshake_timeout: u64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Http2Config {
+    /// Negotiate HTTP/2 using ALPN over TLS, or with prior knowledge (h2c)
+    pub enabled: bool,
+
+    /// Maximum number of concurrent streams per connection
+    pub max_concurrent_streams: u32,
+
+    /// Flow control window for a single stream (in bytes)
+    pub initial_stream_window_size: u32,
+
+    /// Flow control window for the whole connection (in bytes)
+    pub initial_connection_window_size: u32,
+
+    /// Maximum size of the request headers (in bytes)
+    pub max_header_list_size: u32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessLogConfig {
+    pub enabled: bool,
+    pub format: LogFormat,
+
+    /// File to log to, reopened on `SIGHUP`; logs to stdout if missing
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Common,
+    Combined,
+    Json,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct PermissionsConfig {
+    /// Named groups of users, which rules can refer to
+    pub groups: HashMap<String, Vec<String>>,
+
+    /// Checked in order; the first matching rule decides. Without any
+    /// rules, every authenticated user can publish anywhere.
+    pub rules: Vec<RuleConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    pub name: String,
+
+    #[serde(default)]
+    pub effect: RuleEffect,
+
+    /// Users and groups the rule applies to; everyone if both are missing
+    pub users: Option<Vec<String>>,
+    pub groups: Option<Vec<String>>,
+
+    /// Path prefixes (eg. `/blog/alice/`) or globs (eg. `/blog/*/drafts/**`)
+    pub paths: Vec<String>,
+
+    #[serde(default = "default_rule_methods")]
+    pub methods: Vec<PublishMethod>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleEffect {
+    #[default]
+    Allow,
+    Deny,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum PublishMethod {
+    Put,
+    Delete,
+}
+
+impl PublishMethod {
+    pub fn to_method(self) -> Method {
+        match self {
+            PublishMethod::Put => Method::PUT,
+            PublishMethod::Delete => Method::DELETE,
+        }
+    }
+}
+
+impl AuthConfig {
+    /// Whether there's anyone who could publish at all.
+    pub fn has_credentials(&self) -> bool {
+        self.username.is_some() || self.credentials_file.is_some() || self.tokens_file.is_some()
+    }
+}
+
+pub fn default_rule_methods() -> Vec<PublishMethod> {
+    vec![PublishMethod::Put, PublishMethod::Delete]
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Requests per second per address; 0 (the default) disables rate limiting
+    pub requests_per_second: f64,
+
+    /// Requests an address can make in a row before being limited
+    pub burst: u32,
+
+    /// Stricter limits for some paths, on top of the ones above. The
+    /// first rule that matches the path applies.
+    pub rules: Vec<RateLimitRuleConfig>,
+
+    /// Open connections in total; 0 means unlimited
+    pub max_connections: usize,
+
+    /// Open connections per address; 0 means unlimited
+    pub max_connections_per_ip: usize,
+
+    /// Addresses (eg. `10.0.0.5`) or networks (eg. `10.0.0.0/8`) that
+    /// aren't limited at all, like monitoring hosts
+    pub allowlist: Vec<String>,
+
+    /// Addresses tracked at once; the least recently seen are forgotten first
+    pub max_tracked_addresses: usize,
+
+    /// Seconds a client gets to send the headers of a request; 0 means no limit
+    pub header_read_timeout: u64,
+
+    /// Seconds a connection may go without sending or receiving anything
+    /// before it's closed; 0 means no limit
+    pub idle_timeout: u64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitRuleConfig {
+    pub name: String,
+
+    /// Path prefixes or globs, like in the permission rules
+    pub paths: Vec<String>,
+
+    pub requests_per_second: f64,
+    pub burst: u32,
+}
+
+fn default_tls_address() -> String {
+    String::from("127.0.0.1:4443")
+}
+
+fn default_handshake_timeout() -> u64 {
+    10
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: String::from("127.0.0.1:4000"),
+            scope: String::from("."),
+            dev: false,
+            shutdown_timeout: 30,
+            redirects_file: None,
+            server_name: String::from("dupunkto/waiter (Rust)"),
+            content_language: String::from("en-US"),
+            content_charset: String::from("UTF-8"),
+            cache: CacheConfig::default(),
+            compression: CompressionConfig::default(),
+            auth: AuthConfig::default(),
+            uploads: UploadsConfig::default(),
+            tls: None,
+            http2: Http2Config::default(),
+            access_log: AccessLogConfig::default(),
+            permissions: PermissionsConfig::default(),
+            limits: LimitsConfig::default(),
+            autoindex: AutoindexConfig::default(),
+            fancy_urls: FancyUrlsConfig::default(),
+            render: RenderConfig::default(),
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            assets: 31536000,
+            content: 43200,
+        }
+    }
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig { min_size: 1024 }
+    }
+}
+
+impl Default for FancyUrlsConfig {
+    fn default() -> Self {
+        FancyUrlsConfig {
+            extensions: ["htmd", "txt", "html", "xml"].map(String::from).to_vec(),
+            indexes: vec![String::from("index")],
+            canonical_redirects: false,
+            strict_negotiation: false,
+            directories: Vec::new(),
+        }
+    }
+}
+
+impl Default for RenderConfig {
+    fn default() -> Self {
+        RenderConfig {
+            enabled: false,
+            template_name: String::from("_template.html"),
+            cache_size: 256,
+        }
+    }
+}
+
+impl Default for AutoindexConfig {
+    fn default() -> Self {
+        AutoindexConfig {
+            enabled: false,
+            show_hidden: false,
+            opt_out_file: String::from(".noindex"),
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            realm: String::from("PUT"),
+            username: None,
+            password: None,
+            credentials_file: None,
+            tokens_file: None,
+            lockout: LockoutConfig::default(),
+        }
+    }
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        LockoutConfig {
+            enabled: true,
+            max_failures: 5,
+            lockout_time: 60,
+            max_lockout_time: 60 * 60,
+            reset_after: 15 * 60,
+            max_tracked_addresses: 10_000,
+        }
+    }
+}
+
+impl Default for UploadsConfig {
+    fn default() -> Self {
+        UploadsConfig {
+            max_size: 100 * 1024 * 1024,
+            prune_empty_directories: false,
+        }
+    }
+}
+
+impl Default for Http2Config {
+    fn default() -> Self {
+        Http2Config {
+            enabled: true,
+            max_concurrent_streams: 200,
+            initial_stream_window_size: 1024 * 1024,
+            initial_connection_window_size: 1024 * 1024,
+            max_header_list_size: 16 * 1024,
+        }
+    }
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        AccessLogConfig {
+            enabled: true,
+            format: LogFormat::Combined,
+            path: None,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            requests_per_second: 0.0,
+            burst: 100,
+            rules: Vec::new(),
+            max_connections: 1024,
+            max_connections_per_ip: 64,
+            allowlist: Vec::new(),
+            max_tracked_addresses: 10_000,
+            header_read_timeout: 30,
+            idle_timeout: 60,
+        }
+    }
+}
+
+/// Returns the active configuration. Panics if `init` hasn't been called yet.
+pub fn get() -> &'static Config {
+    CONFIG.get().expect("Configuration should be initialized at startup!")
+}
+
+pub fn init(config: Config) {
+    CONFIG
+        .set(config)
+        .expect("Configuration should only be initialized once!");
+}
+
+/// Loads the configuration from `path`, or from the default location
+/// if no path was given. A missing default config file is not an error.
+pub fn load(path: Option<&Path>) -> Result<Config, String> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => match default_path().filter(|path| path.exists()) {
+            Some(path) => path,
+            None => return Ok(Config::default()),
+        },
+    };
+
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|error| format!("Couldn't read config file {}: {error}", path.display()))?;
+
+    toml::from_str(&contents)
+        .map_err(|error| format!("Invalid config file {}: {error}", path.display()))
+}
+
+/// `$XDG_CONFIG_HOME/waiter/config.toml`, or `~/.config/waiter/config.toml`.
+fn default_path() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+
+    Some(config_home.join("waiter").join("config.toml"))
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), String> {
+        self.address
+            .parse::<SocketAddr>()
+            .map_err(|_| format!("Invalid address `{}`; expected eg. `127.0.0.1:4000`.", self.address))?;
+
+        if let Some(tls) = &self.tls {
+            tls.address
+                .parse::<SocketAddr>()
+                .map_err(|_| format!("Invalid tls.address `{}`; expected eg. `127.0.0.1:4443`.", tls.address))?;
+
+            if tls.handshake_timeout == 0 {
+                return Err(String::from("Invalid tls.handshake_timeout; it must be at least 1 second."));
+            }
+        }
+
+        // Window sizes above 2^31 - 1 are a protocol error in HTTP/2.
+        if self.http2.initial_stream_window_size > i32::MAX as u32
+            || self.http2.initial_connection_window_size > i32::MAX as u32
+        {
+            return Err(String::from("Invalid http2 window size; it can be at most 2147483647 bytes."));
+        }
+
+        if self.http2.max_concurrent_streams == 0 {
+            return Err(String::from("Invalid http2.max_concurrent_streams; it must be at least 1."));
+        }
+
+        if !Path::new(&self.scope).is_dir() {
+            return Err(format!("Invalid scope `{}`; not a directory.", self.scope));
+        }
+
+        validate_header_value("server_name", &self.server_name)?;
+        validate_header_value("content_language", &self.content_language)?;
+        validate_header_value("content_charset", &self.content_charset)?;
+        validate_header_value("auth.realm", &self.auth.realm)?;
+
+        match (&self.auth.username, &self.auth.password) {
+            (Some(username), _) if username.is_empty() || username.contains(':') => {
+                return Err(String::from("Invalid auth.username; it can't be empty or contain `:`."));
+            }
+            (_, Some(password)) if password.is_empty() => {
+                return Err(String::from("Invalid auth.password; it can't be empty."));
+            }
+            (Some(_), None) | (None, Some(_)) => {
+                return Err(String::from("Invalid auth; username and password must be set together."));
+            }
+            _ => (),
+        }
+
+        if self.auth.lockout.max_failures == 0 || self.auth.lockout.max_tracked_addresses == 0 {
+            return Err(String::from(
+                "Invalid auth.lockout; max_failures and max_tracked_addresses must be at least 1.",
+            ));
+        }
+
+        let mut rates = std::iter::once((self.limits.requests_per_second, self.limits.burst))
+            .chain(self.limits.rules.iter().map(|rule| (rule.requests_per_second, rule.burst)));
+
+        // Slower rates would make the `Retry-After` delay overflow.
+        if rates.any(|(rate, burst)| {
+            !rate.is_finite() || (rate != 0.0 && rate < MIN_REQUESTS_PER_SECOND) || (rate > 0.0 && burst == 0)
+        }) {
+            return Err(format!(
+                "Invalid rate limit; requests_per_second must be 0 or at least {MIN_REQUESTS_PER_SECOND}, and burst must be at least 1.",
+            ));
+        }
+
+        if self.limits.max_tracked_addresses == 0 {
+            return Err(String::from("Invalid limits.max_tracked_addresses; it must be at least 1."));
+        }
+
+        Ok(())
+    }
+}
+
+fn validate_header_value(key: &str, value: &str) -> Result<(), String> {
+    if value.is_empty() || HeaderValue::from_str(value).is_err() {
+        return Err(format!("Invalid {key} `{value}`; it must be a non-empty, printable ASCII string."));
+    }
+
+    Ok(())
+}
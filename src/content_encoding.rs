@@ -1,35 +1,186 @@
-// Implementation stolen from rouille
+// Originally stolen from rouille
 // MIT licensed.
 
-use http_body_util::Full;
-use hyper::body::Bytes;
-use hyper::{Request, Response};
+use crate::body::{self, Body};
+use crate::config;
+use crate::response::validators;
+
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder, ZstdEncoder};
+use async_compression::Level;
+use hyper::body::Body as _;
 use hyper::header::HeaderValue;
+use hyper::{Method, Request, Response, StatusCode};
 
-type Resp = Response<Full<Bytes>>;
+type Resp = Response<Body>;
 type Req = Request<hyper::body::Incoming>;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// Extension of precompressed files in this encoding, eg. `page.htmd.br`.
+    pub fn file_extension(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zst",
+            Encoding::Gzip => "gz",
+        }
+    }
+
+    fn aliases(&self) -> &'static [&'static str] {
+        match self {
+            Encoding::Brotli => &["br"],
+            Encoding::Zstd => &["zstd"],
+            Encoding::Gzip => &["gzip", "x-gzip"],
+        }
+    }
+}
+
+/// Encodings we support, in order of preference when the client
+/// doesn't care which one it gets.
+const ENCODING_PREFERENCE: [Encoding; 3] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
 pub fn apply(response: &mut Resp, request: &Req) {
-    if !content_is_text(response) || has_content_encoding_header(response) {
-        return
+    if !content_is_text(response) || has_content_encoding_header(response) || is_partial(response) {
+        return;
+    }
+
+    set_vary_header(response);
+
+    let encoding = match accepted_encodings(request).first() {
+        Some(&encoding) => encoding,
+        None => return,
+    };
+
+    // There's no body to compress, but the client might have cached
+    // the compressed version, so send back the same validator.
+    if response.status() == StatusCode::NOT_MODIFIED {
+        weaken_etag(response);
+        return;
+    }
+
+    if body_size(response).is_some_and(|size| size < config::get().compression.min_size as u64) {
+        return;
+    }
+
+    // A `HEAD` response has no body, but its headers should still match
+    // what a `GET` would get.
+    if request.method() != Method::HEAD {
+        let body = std::mem::replace(response.body_mut(), body::empty());
+        *response.body_mut() = compress(body, encoding);
+    }
+
+    let headers = response.headers_mut();
+    headers.remove("Content-Length");
+    headers.insert("Content-Encoding", HeaderValue::from_static(encoding.name()));
+
+    weaken_etag(response);
+}
+
+/// Returns the encodings the client accepts that we support, the most
+/// preferred one first.
+pub fn accepted_encodings<B>(request: &Request<B>) -> Vec<Encoding> {
+    let header = match request.headers().get("Accept-Encoding") {
+        Some(header) => header.to_str().unwrap_or(""),
+        None => return Vec::new(),
+    };
+
+    let accepted = parse_accept_encoding(header);
+
+    let mut encodings: Vec<(Encoding, f32)> = ENCODING_PREFERENCE
+        .iter()
+        .map(|&encoding| (encoding, quality_for(&accepted, encoding)))
+        .filter(|&(_, quality)| quality > 0.0)
+        .collect();
+
+    // Stable sort, so equal qualities keep our own preference order.
+    encodings.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    encodings.into_iter().map(|(encoding, _)| encoding).collect()
+}
+
+fn parse_accept_encoding(header: &str) -> Vec<(String, f32)> {
+    header
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let coding = params.next()?.trim().to_lowercase();
+
+            if coding.is_empty() {
+                return None;
+            }
+
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|value| value.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            Some((coding, quality.clamp(0.0, 1.0)))
+        })
+        .collect()
+}
+
+fn quality_for(accepted: &[(String, f32)], encoding: Encoding) -> f32 {
+    let exact_match = accepted
+        .iter()
+        .find(|(coding, _)| encoding.aliases().contains(&coding.as_str()));
+    let wildcard = accepted.iter().find(|(coding, _)| coding == "*");
+
+    match exact_match.or(wildcard) {
+        Some(&(_, quality)) => quality,
+        None => 0.0,
+    }
+}
+
+/// Compresses the body while it's being streamed to the client.
+fn compress(body: Body, encoding: Encoding) -> Body {
+    let reader = body::into_reader(body);
+
+    match encoding {
+        Encoding::Brotli => body::from_reader(BrotliEncoder::with_quality(reader, Level::Precise(5))),
+        Encoding::Zstd => body::from_reader(ZstdEncoder::new(reader)),
+        Encoding::Gzip => body::from_reader(GzipEncoder::new(reader)),
     }
+}
+
+/// Prefers `Content-Length`, since the body of a `HEAD` response is
+/// always empty.
+fn body_size(response: &Resp) -> Option<u64> {
+    let content_length = response
+        .headers()
+        .get("Content-Length")
+        .and_then(|header| header.to_str().ok())
+        .and_then(|length| length.parse().ok());
+
+    content_length.or_else(|| response.body().size_hint().exact())
+}
 
-    let _encoding_preference = ["br", "gzip", "x-gzip", "identity"];
+fn weaken_etag(response: &mut Resp) {
+    let headers = response.headers_mut();
 
-    let default_header = HeaderValue::from_static("");
-    let _accept_encoding_header = request.headers().get("Accept-Encoding").unwrap_or(&default_header);
+    let etag = match headers.get("ETag").and_then(|etag| etag.to_str().ok()) {
+        Some(etag) => validators::weaken(etag),
+        None => return,
+    };
 
-    // MAGIC!
-    // if let Some(preferred_index) = input::priority_header_preferred(
-    //     accept_encoding_header,
-    //     encoding_preference.iter().cloned(),
-    // ) {
-    //     match encoding_preference[preferred_index] {
-    //         "br" => brotli(&mut response),
-    //         "gzip" | "x-gzip" => gzip(&mut response),
-    //         _ => (),
-    //     }
-    // }
+    headers.insert("ETag", HeaderValue::from_str(&etag).unwrap());
+}
+
+fn set_vary_header(response: &mut Resp) {
+    response
+        .headers_mut()
+        .append("Vary", HeaderValue::from_static("Accept-Encoding"));
 }
 
 fn content_is_text(response: &mut Resp) -> bool {
@@ -43,6 +194,60 @@ fn content_is_text(response: &mut Resp) -> bool {
         || content_type_header.contains("font")
 }
 
+/// Ranges refer to the uncompressed body, so those must be left alone.
+fn is_partial(response: &mut Resp) -> bool {
+    response.status() == StatusCode::PARTIAL_CONTENT
+        || response.status() == StatusCode::RANGE_NOT_SATISFIABLE
+}
+
 fn has_content_encoding_header(response: &mut Resp) -> bool {
     response.headers().get("Content-Encoding").is_some()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepted(header: &str) -> Vec<Encoding> {
+        let request = Request::builder().header("Accept-Encoding", header).body(()).unwrap();
+        accepted_encodings(&request)
+    }
+
+    #[test]
+    fn parses_codings_and_qualities() {
+        assert_eq!(
+            parse_accept_encoding("GZIP;q=0.5, br , ;q=1, zstd;q=2, identity;q=abc"),
+            vec![
+                (String::from("gzip"), 0.5),
+                (String::from("br"), 1.0),
+                (String::from("zstd"), 1.0),
+                (String::from("identity"), 1.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn prefers_higher_quality() {
+        assert_eq!(accepted("gzip, br;q=0.5"), vec![Encoding::Gzip, Encoding::Brotli]);
+        assert_eq!(accepted("zstd;q=0.9, x-gzip"), vec![Encoding::Gzip, Encoding::Zstd]);
+    }
+
+    #[test]
+    fn equal_qualities_keep_our_preference() {
+        assert_eq!(accepted("gzip, zstd, br"), ENCODING_PREFERENCE.to_vec());
+        assert_eq!(accepted("*"), ENCODING_PREFERENCE.to_vec());
+    }
+
+    #[test]
+    fn zero_quality_excludes() {
+        assert_eq!(accepted("*, br;q=0"), vec![Encoding::Zstd, Encoding::Gzip]);
+        assert_eq!(accepted("gzip;q=0"), Vec::new());
+        assert_eq!(accepted("identity"), Vec::new());
+    }
+
+    #[test]
+    fn nothing_without_an_accept_encoding_header() {
+        let request = Request::builder().body(()).unwrap();
+        assert_eq!(accepted_encodings(&request), Vec::new());
+    }
+}
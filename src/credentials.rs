@@ -0,0 +1,153 @@
+use crate::config::AuthConfig;
+use crate::reload;
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{OnceLock, RwLock};
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use subtle::ConstantTimeEq;
+
+static CREDENTIALS: OnceLock<RwLock<Credentials>> = OnceLock::new();
+
+/// Verified against when the user doesn't exist, so failed logins take
+/// about as long for unknown users as for wrong passwords.
+static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
+/// Users and their password hashes, as read from an htpasswd file.
+#[derive(Debug, Default)]
+struct Credentials {
+    users: HashMap<String, String>,
+}
+
+pub fn init(config: &AuthConfig) -> Result<(), String> {
+    let path = match &config.credentials_file {
+        Some(path) => path.clone(),
+        None => return Ok(()),
+    };
+
+    let credentials = load_credentials_file(&path)?;
+
+    if CREDENTIALS.set(RwLock::new(credentials)).is_err() {
+        return Err(String::from("Credentials should only be initialized once!"));
+    }
+
+    reload::watch_files(vec![path.clone()], move || {
+        let credentials = load_credentials_file(&path)?;
+        let count = credentials.users.len();
+
+        *CREDENTIALS.get().unwrap().write().unwrap() = credentials;
+
+        println!("Reloaded {count} user(s) from {}", path.display());
+        Ok(())
+    });
+
+    Ok(())
+}
+
+/// Checks the password against the credentials file, or against the
+/// single user from the config file if there's no credentials file.
+/// This is slow on purpose, so run it on a blocking thread.
+pub fn verify(config: &AuthConfig, username: &str, password: &str) -> bool {
+    let credentials = match CREDENTIALS.get() {
+        Some(credentials) => credentials,
+        None => {
+            let (expected_username, expected_password) = match (&config.username, &config.password) {
+                (Some(username), Some(password)) => (username, password),
+                _ => return false,
+            };
+
+            let username_matches = username.as_bytes().ct_eq(expected_username.as_bytes());
+            let password_matches = password.as_bytes().ct_eq(expected_password.as_bytes());
+
+            return (username_matches & password_matches).into();
+        }
+    };
+
+    let hash = credentials.read().unwrap().users.get(username).cloned();
+
+    match hash {
+        Some(hash) => verify_hash(password, &hash),
+        None => {
+            let _ = verify_hash(password, dummy_hash());
+            false
+        }
+    }
+}
+
+fn dummy_hash() -> &'static str {
+    DUMMY_HASH.get_or_init(|| bcrypt::hash("waiter", bcrypt::DEFAULT_COST).unwrap_or_default())
+}
+
+fn verify_hash(password: &str, hash: &str) -> bool {
+    match hash_scheme(hash) {
+        Some(HashScheme::Bcrypt) => bcrypt::verify(password, hash).unwrap_or(false),
+        Some(HashScheme::Argon2) => PasswordHash::new(hash)
+            .map(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+            .unwrap_or(false),
+        Some(HashScheme::Sha256Crypt) => sha_crypt::sha256_check(password, hash).is_ok(),
+        Some(HashScheme::Sha512Crypt) => sha_crypt::sha512_check(password, hash).is_ok(),
+        None => false,
+    }
+}
+
+enum HashScheme {
+    Bcrypt,
+    Argon2,
+    Sha256Crypt,
+    Sha512Crypt,
+}
+
+fn hash_scheme(hash: &str) -> Option<HashScheme> {
+    let prefixes = [
+        ("$2a$", HashScheme::Bcrypt),
+        ("$2b$", HashScheme::Bcrypt),
+        ("$2x$", HashScheme::Bcrypt),
+        ("$2y$", HashScheme::Bcrypt),
+        ("$argon2", HashScheme::Argon2),
+        ("$5$", HashScheme::Sha256Crypt),
+        ("$6$", HashScheme::Sha512Crypt),
+    ];
+
+    prefixes
+        .into_iter()
+        .find(|(prefix, _)| hash.starts_with(prefix))
+        .map(|(_, scheme)| scheme)
+}
+
+/// Parses an htpasswd file: one `username:hash` pair per line, with
+/// empty lines and `#` comments ignored.
+fn load_credentials_file(path: &Path) -> Result<Credentials, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|error| format!("Couldn't read credentials file {}: {error}", path.display()))?;
+
+    let mut credentials = Credentials::default();
+
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invalid_line = |reason: &str| {
+            format!("Invalid credentials file {}, line {}: {reason}", path.display(), index + 1)
+        };
+
+        let (username, hash) = line
+            .split_once(':')
+            .ok_or_else(|| invalid_line("expected `username:hash`."))?;
+
+        if username.is_empty() {
+            return Err(invalid_line("the username can't be empty."));
+        }
+
+        if hash_scheme(hash).is_none() {
+            return Err(invalid_line("unsupported hash; use bcrypt, argon2 or SHA-crypt."));
+        }
+
+        credentials.users.insert(username.to_string(), hash.to_string());
+    }
+
+    Ok(credentials)
+}
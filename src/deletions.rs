@@ -0,0 +1,71 @@
+use crate::body::Body;
+use crate::config;
+use crate::files;
+use crate::response;
+
+use std::convert::Infallible;
+use std::path::Path;
+
+use hyper::{Request, Response};
+
+type Resp = Response<Body>;
+type Req = Request<hyper::body::Incoming>;
+
+pub async fn process_delete_request(request: Req) -> Result<Resp, Infallible> {
+    // Symlinks aren't followed, so deleting one removes the link itself.
+    let path_buffer = match files::get_path_buffer_for_link_path(request.uri().path()) {
+        Some(path_buffer) => path_buffer,
+        None => {
+            return Ok(response::serve(
+                403,
+                "Forbidden; you cannot delete outside of scope.",
+            ))
+        }
+    };
+
+    match path_buffer.symlink_metadata() {
+        Err(_) => return Ok(response::serve_404()),
+        Ok(metadata) if metadata.is_dir() => {
+            return Ok(response::serve(
+                403,
+                "Forbidden; only files can be deleted, not directories.",
+            ))
+        }
+        Ok(_) => (),
+    }
+
+    if let Err(error) = files::remove_file(&path_buffer).await {
+        eprintln!("{error}");
+
+        return Ok(response::serve(
+            500,
+            "Internal server error; couldn't delete the file.",
+        ));
+    }
+
+    if config::get().uploads.prune_empty_directories {
+        prune_empty_parent_directories(&path_buffer).await;
+    }
+
+    Ok(response::serve(204, ""))
+}
+
+/// Removes the parent directories of `path` that became empty, up to
+/// (but never including) the scope itself.
+async fn prune_empty_parent_directories(path: &Path) {
+    let scope = match Path::new(&config::get().scope).canonicalize() {
+        Ok(scope) => scope,
+        Err(_) => return,
+    };
+
+    for directory in path.ancestors().skip(1) {
+        if directory == scope || !directory.starts_with(&scope) {
+            break;
+        }
+
+        // Fails if the directory isn't empty, which is exactly when we stop.
+        if tokio::fs::remove_dir(directory).await.is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use crate::config::FancyUrlsConfig;
+use crate::permissions::{self, PathPattern};
+
+use std::sync::OnceLock;
+
+static FALLBACKS: OnceLock<Fallbacks> = OnceLock::new();
+
+struct Fallbacks {
+    default: Fallback,
+    directories: Vec<(Vec<PathPattern>, Fallback)>,
+}
+
+/// What to try when a path doesn't exist as is.
+pub struct Fallback {
+    /// Without the leading `.`, eg. `htmd`
+    pub extensions: Vec<String>,
+    pub indexes: Vec<String>,
+}
+
+pub fn init(config: &FancyUrlsConfig) -> Result<(), String> {
+    let default = Fallback {
+        extensions: parse_extensions(&config.extensions)?,
+        indexes: parse_indexes(&config.indexes)?,
+    };
+
+    let directories = config
+        .directories
+        .iter()
+        .map(|directory| {
+            let paths = directory
+                .paths
+                .iter()
+                .map(|path| permissions::compile_path_pattern(path))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|error| format!("Invalid fancy_urls.directories entry: {error}"))?;
+
+            let fallback = Fallback {
+                extensions: match &directory.extensions {
+                    Some(extensions) => parse_extensions(extensions)?,
+                    None => default.extensions.clone(),
+                },
+                indexes: match &directory.indexes {
+                    Some(indexes) => parse_indexes(indexes)?,
+                    None => default.indexes.clone(),
+                },
+            };
+
+            Ok((paths, fallback))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    if FALLBACKS.set(Fallbacks { default, directories }).is_err() {
+        return Err(String::from("Fancy URLs should only be initialized once!"));
+    }
+
+    Ok(())
+}
+
+/// Returns the fallback for the first directory entry matching the
+/// request path, or the global one.
+pub fn fallback_for(request_path: &str) -> &'static Fallback {
+    let fallbacks = FALLBACKS.get().expect("Fancy URLs should be initialized before use!");
+    let path = permissions::normalize_request_path(request_path);
+
+    fallbacks
+        .directories
+        .iter()
+        .find(|(patterns, _)| patterns.iter().any(|pattern| pattern.matches(&path)))
+        .map(|(_, fallback)| fallback)
+        .unwrap_or(&fallbacks.default)
+}
+
+/// Accepts extensions with or without the leading `.`.
+fn parse_extensions(extensions: &[String]) -> Result<Vec<String>, String> {
+    extensions
+        .iter()
+        .map(|extension| {
+            let extension = extension.strip_prefix('.').unwrap_or(extension);
+
+            if extension.is_empty() || extension.contains('/') {
+                return Err(format!("Invalid extension `{extension}` in fancy_urls."));
+            }
+
+            Ok(extension.to_string())
+        })
+        .collect()
+}
+
+fn parse_indexes(indexes: &[String]) -> Result<Vec<String>, String> {
+    indexes
+        .iter()
+        .map(|index| {
+            if index.is_empty() || index.contains('/') {
+                return Err(format!("Invalid index `{index}` in fancy_urls; it should be a file name."));
+            }
+
+            Ok(index.clone())
+        })
+        .collect()
+}
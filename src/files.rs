@@ -1,29 +1,86 @@
 use crate::config;
 
-use std::path::Path;
-use std::path::PathBuf;
+use std::fs::Metadata;
+use std::io::SeekFrom;
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use hyper::body::Bytes;
 use hyper::Uri;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, Take};
 
-pub async fn read_file(filename: &str) -> Result<Bytes, String> {
-    if let Ok(contents) = tokio::fs::read(filename).await {
-        let body: Bytes = contents.into();
-        return Ok(body);
+/// Opens `filename` for reading `length` bytes, starting at `start`.
+pub async fn open_file_range(filename: &str, start: u64, length: u64) -> Result<Take<File>, String> {
+    let error = format!("File {filename} not found or couldn't read it.");
+
+    let mut file = File::open(filename).await.map_err(|_| error.clone())?;
+    file.seek(SeekFrom::Start(start)).await.map_err(|_| error)?;
+
+    Ok(file.take(length))
+}
+
+pub async fn read_file_metadata(filename: &str) -> Result<Metadata, String> {
+    match tokio::fs::metadata(filename).await {
+        Ok(metadata) if metadata.is_file() => Ok(metadata),
+        _ => Err(format!("File {filename} not found or couldn't read it.")),
     }
+}
 
-    Err(format!("File {filename} not found or couldn't read it."))
+/// Creates an empty temporary file next to `filename`, so it can later be
+/// renamed into place atomically using `persist_temp_file`.
+pub async fn create_temp_file(filename: &str) -> Result<(PathBuf, File), String> {
+    let error = format!("An unknown error occured while trying to write {filename}.");
+
+    let path = Path::new(filename);
+    let name = path.file_name().ok_or(error.clone())?.to_string_lossy();
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or_default();
+
+    let temp_path = path.with_file_name(format!(".{name}.{nanos:x}.tmp"));
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp_path)
+        .await
+        .map_err(|_| error)?;
+
+    Ok((temp_path, file))
 }
 
-pub async fn write_file(filename: &str, bytes: Bytes) -> Result<(), String> {
+pub async fn persist_temp_file(temp_path: &Path, file: File, filename: &str) -> Result<(), String> {
     let error = format!("An unknown error occured while trying to write {filename}.");
-    tokio::fs::write(filename, bytes).await.map_err(|_| error)?;
+
+    file.sync_all().await.map_err(|_| error.clone())?;
+    drop(file);
+
+    tokio::fs::rename(temp_path, filename).await.map_err(|_| error)?;
+
+    Ok(())
+}
+
+pub async fn remove_file(filename: &Path) -> Result<(), String> {
+    let error = format!("An unknown error occured while trying to remove {}.", filename.display());
+    tokio::fs::remove_file(filename).await.map_err(|_| error)?;
+
+    Ok(())
+}
+
+pub async fn create_parent_directories(filename: &str) -> Result<(), String> {
+    let parent = match Path::new(filename).parent() {
+        Some(parent) => parent,
+        None => return Ok(()),
+    };
+
+    let error = format!("An unknown error occured while trying to create {}.", parent.display());
+    tokio::fs::create_dir_all(parent).await.map_err(|_| error)?;
 
     Ok(())
 }
 
 pub fn get_path_buffer_for_allowed_path(path: &str) -> Option<PathBuf> {
-    let scope = Path::new(config::SCOPE).canonicalize().ok()?;
+    let scope = Path::new(&config::get().scope).canonicalize().ok()?;
     let path = Path::new(path).canonicalize();
 
     let path_buffer = path.ok()?;
@@ -35,6 +92,216 @@ pub fn get_path_buffer_for_allowed_path(path: &str) -> Option<PathBuf> {
     Some(path_buffer)
 }
 
-pub fn uri_to_local_path(uri: &Uri) -> String {
-    format!(".{}", uri.path())
+/// Like `get_path_buffer_for_allowed_path`, but also works for paths that
+/// don't exist yet. `path` is relative to the scope (eg. a request path).
+/// It is normalized without touching the disk, after
+/// which the nearest existing ancestor is canonicalized to make sure no
+/// symlink leads outside of scope.
+pub fn get_path_buffer_for_new_path(path: &str) -> Option<PathBuf> {
+    let scope = Path::new(&config::get().scope).canonicalize().ok()?;
+    resolve_new_path(&scope, path)
+}
+
+fn resolve_new_path(scope: &Path, path: &str) -> Option<PathBuf> {
+    let path_buffer = normalize_path(scope, path)?;
+
+    ancestors_stay_in_scope(scope, &path_buffer).then_some(path_buffer)
+}
+
+/// Like `get_path_buffer_for_new_path`, but if the path itself is a
+/// symlink it isn't followed, so it refers to the link rather than to
+/// what it points to. Only its parent has to be inside of scope.
+pub fn get_path_buffer_for_link_path(path: &str) -> Option<PathBuf> {
+    let scope = Path::new(&config::get().scope).canonicalize().ok()?;
+    resolve_link_path(&scope, path)
+}
+
+fn resolve_link_path(scope: &Path, path: &str) -> Option<PathBuf> {
+    let path_buffer = normalize_path(scope, path)?;
+
+    // The parent of the scope itself is outside of it, so the scope
+    // can never be referred to this way.
+    ancestors_stay_in_scope(scope, path_buffer.parent()?).then_some(path_buffer)
+}
+
+/// Canonicalizes the nearest existing ancestor of `path` (which may be
+/// `path` itself), to make sure no symlink leads outside of scope.
+fn ancestors_stay_in_scope(scope: &Path, path: &Path) -> bool {
+    let existing_ancestor = match path.ancestors().find(|ancestor| ancestor.symlink_metadata().is_ok()) {
+        Some(ancestor) => ancestor,
+        None => return false,
+    };
+
+    existing_ancestor
+        .canonicalize()
+        .is_ok_and(|ancestor| ancestor.starts_with(scope))
+}
+
+/// Percent-decodes the request path `path`, and resolves `.` and `..`
+/// in it lexically, relative to `scope`. Returns `None` if the path would
+/// end up outside of scope.
+pub fn normalize_path(scope: &Path, path: &str) -> Option<PathBuf> {
+    let path = percent_decode(path)?;
+    let mut normalized = scope.to_path_buf();
+
+    for component in Path::new(&path).components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::ParentDir => {
+                if normalized == scope {
+                    return None;
+                }
+
+                normalized.pop();
+            }
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => (),
+        }
+    }
+
+    Some(normalized)
+}
+
+/// The local path a request refers to, or `None` if it's outside of scope
+/// (eg. `/../secret`). Symlinks still need to be checked when it's used.
+pub fn uri_to_local_path(uri: &Uri) -> Option<String> {
+    let scope = Path::new(&config::get().scope);
+    let path = normalize_path(scope, uri.path())?;
+
+    path.to_str().map(str::to_string)
+}
+
+/// Decodes `%XX` escapes, so `/my%20file.txt` refers to `my file.txt`.
+/// Returns `None` if an escape is invalid, or if the result isn't UTF-8
+/// or contains a null byte.
+pub fn percent_decode(path: &str) -> Option<String> {
+    if !path.contains('%') {
+        return Some(path.to_string());
+    }
+
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut bytes = path.bytes();
+
+    while let Some(byte) = bytes.next() {
+        if byte != b'%' {
+            decoded.push(byte);
+            continue;
+        }
+
+        let high = (bytes.next()? as char).to_digit(16)?;
+        let low = (bytes.next()? as char).to_digit(16)?;
+        decoded.push((high * 16 + low) as u8);
+    }
+
+    String::from_utf8(decoded).ok().filter(|decoded| !decoded.contains('\0'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::symlink;
+
+    /// A fresh, canonicalized directory to use as the scope.
+    fn temp_scope(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("waiter-files-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        directory.canonicalize().unwrap()
+    }
+
+    #[test]
+    fn normalize_resolves_dots_within_scope() {
+        let scope = Path::new("/srv/site");
+
+        assert_eq!(normalize_path(scope, "/a/./b/../c"), Some(scope.join("a/c")));
+        assert_eq!(normalize_path(scope, "/a/.."), Some(scope.to_path_buf()));
+        assert_eq!(normalize_path(scope, "/"), Some(scope.to_path_buf()));
+    }
+
+    #[test]
+    fn normalize_rejects_parent_of_scope() {
+        let scope = Path::new("/srv/site");
+
+        assert_eq!(normalize_path(scope, "/.."), None);
+        assert_eq!(normalize_path(scope, "/../site/a"), None);
+        assert_eq!(normalize_path(scope, "/a/../../b"), None);
+    }
+
+    #[test]
+    fn normalize_decodes_before_resolving_dots() {
+        let scope = Path::new("/srv/site");
+
+        assert_eq!(normalize_path(scope, "/my%20file.txt"), Some(scope.join("my file.txt")));
+        assert_eq!(normalize_path(scope, "/a/%2e%2e/b"), Some(scope.join("b")));
+        assert_eq!(normalize_path(scope, "/a%2F..%2F..%2Fetc"), None);
+    }
+
+    #[test]
+    fn percent_decodes_paths() {
+        assert_eq!(percent_decode("/caf%C3%A9/a%2fb"), Some(String::from("/café/a/b")));
+        assert_eq!(percent_decode("/plain"), Some(String::from("/plain")));
+        assert_eq!(percent_decode("/bad%2"), None);
+        assert_eq!(percent_decode("/bad%zz"), None);
+        assert_eq!(percent_decode("/bad%FF"), None);
+        assert_eq!(percent_decode("/bad%00"), None);
+    }
+
+    #[test]
+    fn new_path_can_be_in_missing_directories() {
+        let scope = temp_scope("missing");
+
+        assert_eq!(resolve_new_path(&scope, "/a/b/c.txt"), Some(scope.join("a/b/c.txt")));
+    }
+
+    #[test]
+    fn new_path_rejects_symlinked_ancestor_outside_scope() {
+        let scope = temp_scope("ancestor");
+        let outside = temp_scope("ancestor-outside");
+        symlink(&outside, scope.join("link")).unwrap();
+
+        assert_eq!(resolve_new_path(&scope, "/link/new.txt"), None);
+        assert_eq!(resolve_new_path(&scope, "/link/a/b/new.txt"), None);
+    }
+
+    #[test]
+    fn new_path_allows_symlinked_ancestor_inside_scope() {
+        let scope = temp_scope("inside");
+        std::fs::create_dir(scope.join("real")).unwrap();
+        symlink(scope.join("real"), scope.join("link")).unwrap();
+
+        assert_eq!(resolve_new_path(&scope, "/link/new.txt"), Some(scope.join("link/new.txt")));
+    }
+
+    #[test]
+    fn new_path_rejects_dangling_symlink() {
+        let scope = temp_scope("dangling");
+        let outside = temp_scope("dangling-outside");
+        symlink(outside.join("missing.txt"), scope.join("dangling.txt")).unwrap();
+        symlink(scope.join("missing"), scope.join("dangling-dir")).unwrap();
+
+        // Writing through it would create the target, wherever that is.
+        assert_eq!(resolve_new_path(&scope, "/dangling.txt"), None);
+        assert_eq!(resolve_new_path(&scope, "/dangling-dir/new.txt"), None);
+    }
+
+    #[test]
+    fn link_path_refers_to_the_link_itself() {
+        let scope = temp_scope("link");
+        let outside = temp_scope("link-outside");
+        symlink(&outside, scope.join("link")).unwrap();
+        symlink(outside.join("missing.txt"), scope.join("dangling.txt")).unwrap();
+
+        assert_eq!(resolve_link_path(&scope, "/link"), Some(scope.join("link")));
+        assert_eq!(resolve_link_path(&scope, "/dangling.txt"), Some(scope.join("dangling.txt")));
+        assert_eq!(resolve_link_path(&scope, "/link/file.txt"), None);
+    }
+
+    #[test]
+    fn link_path_cannot_be_the_scope() {
+        let scope = temp_scope("link-scope");
+
+        assert_eq!(resolve_link_path(&scope, "/"), None);
+        assert_eq!(resolve_link_path(&scope, "/a/.."), None);
+        assert_eq!(resolve_link_path(&scope, "/a/b"), Some(scope.join("a/b")));
+    }
 }
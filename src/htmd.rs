@@ -0,0 +1,316 @@
+pub struct Page {
+    /// Text of the first heading, if any
+    pub title: Option<String>,
+    pub html: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Block {
+    None,
+    Paragraph,
+    UnorderedList,
+    OrderedList,
+    Quote,
+}
+
+/// Renders a gridsite page to HTML, for browsers that can't display htmd
+/// themselves. Supports headings, paragraphs, lists, quotes, preformatted
+/// blocks, `=> url text` link lines and inline `[text](url)` links.
+pub fn to_html(source: &str) -> Page {
+    let mut html = String::new();
+    let mut title = None;
+    let mut block = Block::None;
+    let mut preformatted = false;
+
+    for line in source.lines() {
+        if let Some(fence) = line.strip_prefix("```") {
+            close_block(&mut html, &mut block);
+
+            if preformatted {
+                html.push_str("</code></pre>\n");
+            } else {
+                // Anything after the fence is treated as alt text.
+                match fence.trim() {
+                    "" => html.push_str("<pre><code>"),
+                    alt => html.push_str(&format!("<pre aria-label=\"{}\"><code>", escape(alt))),
+                }
+            }
+
+            preformatted = !preformatted;
+            continue;
+        }
+
+        if preformatted {
+            html.push_str(&escape(line));
+            html.push('\n');
+            continue;
+        }
+
+        let trimmed = line.trim();
+
+        if trimmed.is_empty() {
+            close_block(&mut html, &mut block);
+        } else if let Some((level, text)) = heading(trimmed) {
+            close_block(&mut html, &mut block);
+
+            if level == 1 && title.is_none() {
+                title = Some(text.to_string());
+            }
+
+            html.push_str(&format!("<h{level}>{}</h{level}>\n", inline(text)));
+        } else if let Some(link) = trimmed.strip_prefix("=>") {
+            close_block(&mut html, &mut block);
+            html.push_str(&format!("<p>{}</p>\n", link_line(link.trim())));
+        } else if let Some(item) = trimmed.strip_prefix("- ").or_else(|| trimmed.strip_prefix("* ")) {
+            open_block(&mut html, &mut block, Block::UnorderedList);
+            html.push_str(&format!("<li>{}</li>\n", inline(item.trim())));
+        } else if let Some(item) = ordered_item(trimmed) {
+            open_block(&mut html, &mut block, Block::OrderedList);
+            html.push_str(&format!("<li>{}</li>\n", inline(item)));
+        } else if let Some(quote) = trimmed.strip_prefix('>') {
+            open_block(&mut html, &mut block, Block::Quote);
+            html.push_str(&format!("<p>{}</p>\n", inline(quote.trim())));
+        } else {
+            if block == Block::Paragraph {
+                html.push_str("<br>\n");
+            } else {
+                open_block(&mut html, &mut block, Block::Paragraph);
+            }
+
+            html.push_str(&inline(trimmed));
+        }
+    }
+
+    close_block(&mut html, &mut block);
+
+    // Don't leave an unterminated block open at the end of the file.
+    if preformatted {
+        html.push_str("</code></pre>\n");
+    }
+
+    Page { title, html }
+}
+
+fn open_block(html: &mut String, block: &mut Block, new_block: Block) {
+    if *block == new_block {
+        return;
+    }
+
+    close_block(html, block);
+
+    html.push_str(match new_block {
+        Block::Paragraph => "<p>",
+        Block::UnorderedList => "<ul>\n",
+        Block::OrderedList => "<ol>\n",
+        Block::Quote => "<blockquote>\n",
+        Block::None => "",
+    });
+
+    *block = new_block;
+}
+
+fn close_block(html: &mut String, block: &mut Block) {
+    html.push_str(match block {
+        Block::Paragraph => "</p>\n",
+        Block::UnorderedList => "</ul>\n",
+        Block::OrderedList => "</ol>\n",
+        Block::Quote => "</blockquote>\n",
+        Block::None => "",
+    });
+
+    *block = Block::None;
+}
+
+/// `## Text` becomes `(2, "Text")`.
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.chars().take_while(|&c| c == '#').count();
+    let text = line[level..].strip_prefix(' ')?;
+
+    (1..=6).contains(&level).then_some((level, text.trim()))
+}
+
+/// `1. Text` becomes `"Text"`.
+fn ordered_item(line: &str) -> Option<&str> {
+    let digits = line.chars().take_while(char::is_ascii_digit).count();
+
+    if digits == 0 {
+        return None;
+    }
+
+    line[digits..].strip_prefix(". ").map(str::trim)
+}
+
+/// `=> url optional text`
+fn link_line(link: &str) -> String {
+    let (url, text) = match link.split_once(char::is_whitespace) {
+        Some((url, text)) => (url, text.trim()),
+        None => (link, link),
+    };
+
+    format!("<a href=\"{}\">{}</a>", escape(&safe_url(url)), escape(text))
+}
+
+/// Escapes the text, and turns `[text](url)` into links and `` `code` ``
+/// into code.
+fn inline(text: &str) -> String {
+    let mut html = String::new();
+    let mut rest = text;
+
+    while let Some(index) = rest.find(['[', '`']) {
+        html.push_str(&escape(&rest[..index]));
+        rest = &rest[index..];
+
+        if let Some(code) = rest.strip_prefix('`') {
+            if let Some(end) = code.find('`') {
+                html.push_str(&format!("<code>{}</code>", escape(&code[..end])));
+                rest = &code[end + 1..];
+                continue;
+            }
+        } else if let Some((label, url, remaining)) = inline_link(rest) {
+            html.push_str(&format!("<a href=\"{}\">{}</a>", escape(&safe_url(url)), escape(label)));
+            rest = remaining;
+            continue;
+        }
+
+        // Not a link or code after all; keep the character as is.
+        html.push_str(&escape(&rest[..1]));
+        rest = &rest[1..];
+    }
+
+    html.push_str(&escape(rest));
+    html
+}
+
+/// Splits `[label](url) remaining` into its parts.
+fn inline_link(text: &str) -> Option<(&str, &str, &str)> {
+    let label_end = text.find("](")?;
+    let label = &text[1..label_end];
+
+    let after_label = &text[label_end + 2..];
+    let url_end = after_label.find(')')?;
+
+    Some((label, &after_label[..url_end], &after_label[url_end + 1..]))
+}
+
+/// Schemes links may use; relative URLs are always fine.
+const SAFE_SCHEMES: [&str; 4] = ["http", "https", "mailto", "gemini"];
+
+/// Scripts don't belong in links, so only relative URLs and known-safe
+/// schemes are kept. Browsers ignore tabs and newlines in URLs (so
+/// `java\tscript:` is still a script), so those are removed first.
+fn safe_url(url: &str) -> String {
+    let url: String = url
+        .chars()
+        .filter(|c| !c.is_ascii_control() && !c.is_ascii_whitespace())
+        .collect();
+
+    // A scheme ends at the first `:`, unless a path, query or fragment
+    // starts before that.
+    let scheme = match url.find([':', '/', '?', '#']) {
+        Some(index) if url[index..].starts_with(':') => Some(&url[..index]),
+        _ => None,
+    };
+
+    match scheme {
+        Some(scheme) if !SAFE_SCHEMES.iter().any(|safe| safe.eq_ignore_ascii_case(scheme)) => String::from("#"),
+        _ => url,
+    }
+}
+
+pub fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_text() {
+        let page = to_html("<script>alert('x')</script> & \"more\"");
+
+        assert_eq!(
+            page.html,
+            "<p>&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt; &amp; &quot;more&quot;</p>\n"
+        );
+    }
+
+    #[test]
+    fn renders_headings_and_title() {
+        let page = to_html("# Hello <you>\n## Sub\n# Second");
+
+        assert_eq!(page.title.as_deref(), Some("Hello <you>"));
+        assert_eq!(page.html, "<h1>Hello &lt;you&gt;</h1>\n<h2>Sub</h2>\n<h1>Second</h1>\n");
+    }
+
+    #[test]
+    fn renders_links() {
+        assert_eq!(
+            to_html("=> /about About me").html,
+            "<p><a href=\"/about\">About me</a></p>\n"
+        );
+        assert_eq!(
+            to_html("See [this](https://example.com/?a=1&b=2).").html,
+            "<p>See <a href=\"https://example.com/?a=1&amp;b=2\">this</a>.</p>\n"
+        );
+        assert_eq!(
+            to_html("[mail](mailto:a@example.com) [other](page:2)").html,
+            "<p><a href=\"mailto:a@example.com\">mail</a> <a href=\"#\">other</a></p>\n"
+        );
+    }
+
+    #[test]
+    fn keeps_relative_urls_with_colons() {
+        assert_eq!(safe_url("/a:b"), "/a:b");
+        assert_eq!(safe_url("page?at=12:00"), "page?at=12:00");
+        assert_eq!(safe_url("#top"), "#top");
+    }
+
+    #[test]
+    fn refuses_script_urls() {
+        assert_eq!(safe_url("javascript:alert(1)"), "#");
+        assert_eq!(safe_url(" JavaScript:alert(1)"), "#");
+        assert_eq!(safe_url("java\tscript:alert(1)"), "#");
+        assert_eq!(safe_url("java\nscript:alert(1)"), "#");
+        assert_eq!(safe_url("\u{1}javascript:alert(1)"), "#");
+        assert_eq!(safe_url("data:text/html,<script>"), "#");
+        assert_eq!(safe_url("vbscript:msgbox"), "#");
+
+        assert_eq!(
+            to_html("[x](java\tscript:alert%28document.domain%29)").html,
+            "<p><a href=\"#\">x</a></p>\n"
+        );
+    }
+
+    #[test]
+    fn renders_lists_and_quotes() {
+        assert_eq!(
+            to_html("- one\n* two\n1. first\n2. second\n> quoted").html,
+            "<ul>\n<li>one</li>\n<li>two</li>\n</ul>\n<ol>\n<li>first</li>\n<li>second</li>\n</ol>\n<blockquote>\n<p>quoted</p>\n</blockquote>\n"
+        );
+    }
+
+    #[test]
+    fn renders_paragraphs_with_line_breaks() {
+        assert_eq!(to_html("one\ntwo\n\nthree").html, "<p>one<br>\ntwo</p>\n<p>three</p>\n");
+    }
+
+    #[test]
+    fn renders_code_fences_verbatim() {
+        assert_eq!(
+            to_html("```rust\n# not a heading\n<b>\n```").html,
+            "<pre aria-label=\"rust\"><code># not a heading\n&lt;b&gt;\n</code></pre>\n"
+        );
+        assert_eq!(to_html("```\nunterminated").html, "<pre><code>unterminated\n</code></pre>\n");
+    }
+
+    #[test]
+    fn renders_inline_code() {
+        assert_eq!(to_html("use `<a>` [here").html, "<p>use <code>&lt;a&gt;</code> [here</p>\n");
+    }
+}
@@ -0,0 +1,110 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use hyper::rt::{Read, ReadBufCursor, Write};
+
+/// Wraps a connection to keep track of when anything was last sent or
+/// received on it, so connections that went quiet can be closed.
+pub struct IdleTracking<I> {
+    inner: I,
+    activity: Activity,
+}
+
+/// When the connection was last used, shared with whoever closes it.
+#[derive(Clone)]
+pub struct Activity {
+    started: Instant,
+    /// Milliseconds since `started`
+    last_active: Arc<AtomicU64>,
+}
+
+pub fn track<I>(inner: I) -> (IdleTracking<I>, Activity) {
+    let activity = Activity {
+        started: Instant::now(),
+        last_active: Arc::new(AtomicU64::new(0)),
+    };
+
+    (IdleTracking { inner, activity: activity.clone() }, activity)
+}
+
+impl Activity {
+    fn touch(&self) {
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        self.last_active.store(elapsed, Ordering::Relaxed);
+    }
+
+    /// Resolves once nothing was sent or received for `timeout`.
+    pub async fn idle_for(&self, timeout: Duration) {
+        loop {
+            let last_active = Duration::from_millis(self.last_active.load(Ordering::Relaxed));
+            let deadline = self.started + last_active + timeout;
+
+            if Instant::now() >= deadline {
+                return;
+            }
+
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+    }
+}
+
+impl<I: Read + Unpin> Read for IdleTracking<I> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: ReadBufCursor<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+
+        if let Poll::Ready(Ok(())) = result {
+            this.activity.touch();
+        }
+
+        result
+    }
+}
+
+impl<I: Write + Unpin> Write for IdleTracking<I> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+
+        if let Poll::Ready(Ok(written)) = result {
+            if written > 0 {
+                this.activity.touch();
+            }
+        }
+
+        result
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write_vectored(cx, bufs);
+
+        if let Poll::Ready(Ok(written)) = result {
+            if written > 0 {
+                this.activity.touch();
+            }
+        }
+
+        result
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
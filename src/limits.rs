@@ -0,0 +1,308 @@
+use crate::config::LimitsConfig;
+use crate::permissions::{self, PathPattern};
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+static LIMITS: OnceLock<Limits> = OnceLock::new();
+
+/// The limits from the config, together with the state to enforce them.
+struct Limits {
+    rate: Option<Rate>,
+    rules: Vec<RateLimitRule>,
+    max_connections: usize,
+    max_connections_per_ip: usize,
+    allowlist: Vec<Network>,
+    max_tracked_addresses: usize,
+
+    /// Keyed by address, and the index of the rule (if any)
+    buckets: Mutex<HashMap<(IpAddr, Option<usize>), Bucket>>,
+    connections: Mutex<Connections>,
+}
+
+#[derive(Clone, Copy)]
+struct Rate {
+    per_second: f64,
+    burst: f64,
+}
+
+struct RateLimitRule {
+    paths: Vec<PathPattern>,
+    rate: Option<Rate>,
+}
+
+/// Token bucket: every request takes a token, and tokens come back at
+/// a fixed rate, up to the burst size.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Default)]
+struct Connections {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// An address with a prefix length, eg. `10.0.0.0/8`.
+struct Network {
+    address: IpAddr,
+    prefix_len: u32,
+}
+
+/// Keeps an accepted connection counted until it's dropped.
+pub struct ConnectionGuard {
+    address: Option<IpAddr>,
+}
+
+pub fn init(config: &LimitsConfig) -> Result<(), String> {
+    let rules = config
+        .rules
+        .iter()
+        .map(|rule| {
+            let paths = rule
+                .paths
+                .iter()
+                .map(|path| permissions::compile_path_pattern(path))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|error| format!("Invalid rate limit rule `{}`: {error}", rule.name))?;
+
+            Ok(RateLimitRule {
+                paths,
+                rate: Rate::new(rule.requests_per_second, rule.burst),
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let allowlist = config
+        .allowlist
+        .iter()
+        .map(|network| parse_network(network))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let limits = Limits {
+        rate: Rate::new(config.requests_per_second, config.burst),
+        rules,
+        max_connections: config.max_connections,
+        max_connections_per_ip: config.max_connections_per_ip,
+        allowlist,
+        max_tracked_addresses: config.max_tracked_addresses,
+        buckets: Mutex::new(HashMap::new()),
+        connections: Mutex::new(Connections::default()),
+    };
+
+    if LIMITS.set(limits).is_err() {
+        return Err(String::from("Limits should only be initialized once!"));
+    }
+
+    Ok(())
+}
+
+/// Counts a new connection, or returns `None` if there are too many
+/// already, in total or from this address.
+pub fn acquire_connection(address: IpAddr) -> Option<ConnectionGuard> {
+    let limits = match LIMITS.get() {
+        Some(limits) if !limits.is_allowlisted(address) => limits,
+        _ => return Some(ConnectionGuard { address: None }),
+    };
+
+    let mut connections = limits.connections.lock().unwrap();
+    let from_address = connections.per_ip.get(&address).copied().unwrap_or(0);
+
+    if limits.max_connections > 0 && connections.total >= limits.max_connections {
+        println!("Refused connection from {address}; already {} connections open", connections.total);
+        return None;
+    }
+
+    if limits.max_connections_per_ip > 0 && from_address >= limits.max_connections_per_ip {
+        println!("Refused connection from {address}; already {from_address} connections open from there");
+        return None;
+    }
+
+    connections.total += 1;
+    connections.per_ip.insert(address, from_address + 1);
+
+    Some(ConnectionGuard { address: Some(address) })
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let (address, limits) = match (self.address, LIMITS.get()) {
+            (Some(address), Some(limits)) => (address, limits),
+            _ => return,
+        };
+
+        let mut connections = limits.connections.lock().unwrap();
+        connections.total -= 1;
+
+        if let Some(count) = connections.per_ip.get_mut(&address) {
+            *count -= 1;
+
+            if *count == 0 {
+                connections.per_ip.remove(&address);
+            }
+        }
+    }
+}
+
+/// Takes a token for the address, and for the first rule matching the
+/// path. If either bucket is empty, returns how long to wait.
+pub fn check_rate(address: IpAddr, path: &str) -> Result<(), Duration> {
+    let limits = match LIMITS.get() {
+        Some(limits) if !limits.is_allowlisted(address) => limits,
+        _ => return Ok(()),
+    };
+
+    let path = permissions::normalize_request_path(path);
+    let rule = limits
+        .rules
+        .iter()
+        .enumerate()
+        .find(|(_, rule)| rule.paths.iter().any(|pattern| pattern.matches(&path)));
+
+    let mut buckets = limits.buckets.lock().unwrap();
+    let now = Instant::now();
+
+    if let Some(rate) = limits.rate {
+        limits.take_token(&mut buckets, (address, None), rate, now)?;
+    }
+
+    if let Some((index, RateLimitRule { rate: Some(rate), .. })) = rule {
+        limits.take_token(&mut buckets, (address, Some(index)), *rate, now)?;
+    }
+
+    Ok(())
+}
+
+impl Limits {
+    fn is_allowlisted(&self, address: IpAddr) -> bool {
+        self.allowlist.iter().any(|network| network.contains(address))
+    }
+
+    fn take_token(
+        &self,
+        buckets: &mut HashMap<(IpAddr, Option<usize>), Bucket>,
+        key: (IpAddr, Option<usize>),
+        rate: Rate,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        if !buckets.contains_key(&key) {
+            self.make_room(buckets, now);
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: rate.burst,
+            updated: now,
+        });
+
+        bucket.refill(rate, now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate.per_second))
+        }
+    }
+
+    /// Keeps memory use bounded: forgets buckets that have filled up
+    /// again first (they're no different from new ones), and then the
+    /// least recently used.
+    fn make_room(&self, buckets: &mut HashMap<(IpAddr, Option<usize>), Bucket>, now: Instant) {
+        if buckets.len() < self.max_tracked_addresses {
+            return;
+        }
+
+        buckets.retain(|(_, rule), bucket| {
+            let rate = match rule {
+                Some(index) => self.rules[*index].rate,
+                None => self.rate,
+            };
+
+            rate.is_some_and(|rate| !bucket.is_full(rate, now))
+        });
+
+        while buckets.len() >= self.max_tracked_addresses {
+            let oldest = buckets
+                .iter()
+                .min_by_key(|(_, bucket)| bucket.updated)
+                .map(|(key, _)| *key);
+
+            match oldest {
+                Some(key) => buckets.remove(&key),
+                None => break,
+            };
+        }
+    }
+}
+
+impl Rate {
+    /// A rate of 0 means there's no limit.
+    fn new(per_second: f64, burst: u32) -> Option<Rate> {
+        (per_second > 0.0).then_some(Rate {
+            per_second,
+            burst: f64::from(burst),
+        })
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: Rate, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * rate.per_second).min(rate.burst);
+        self.updated = now;
+    }
+
+    fn is_full(&self, rate: Rate, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * rate.per_second >= rate.burst
+    }
+}
+
+impl Network {
+    fn contains(&self, address: IpAddr) -> bool {
+        match (self.address, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                prefix_matches(network.to_bits().into(), address.to_bits().into(), self.prefix_len, 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                prefix_matches(network.to_bits(), address.to_bits(), self.prefix_len, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: u128, address: u128, prefix_len: u32, bits: u32) -> bool {
+    let shift = bits - prefix_len;
+
+    // Shifting by the full width would overflow; a /0 matches everything.
+    shift >= bits || (network >> shift) == (address >> shift)
+}
+
+/// Parses `10.0.0.5`, `10.0.0.0/8` or `fd00::/8`.
+fn parse_network(value: &str) -> Result<Network, String> {
+    let invalid = || format!("Invalid limits.allowlist entry `{value}`; expected eg. `10.0.0.5` or `10.0.0.0/8`.");
+
+    let (address, prefix_len) = match value.split_once('/') {
+        Some((address, prefix_len)) => (address, Some(prefix_len)),
+        None => (value, None),
+    };
+
+    let address: IpAddr = address.trim().parse().map_err(|_| invalid())?;
+    let bits = if address.is_ipv4() { 32 } else { 128 };
+
+    let prefix_len = match prefix_len {
+        Some(prefix_len) => prefix_len.trim().parse().map_err(|_| invalid())?,
+        None => bits,
+    };
+
+    if prefix_len > bits {
+        return Err(invalid());
+    }
+
+    Ok(Network { address: address.to_canonical(), prefix_len })
+}
@@ -0,0 +1,127 @@
+use crate::config::{self, LockoutConfig};
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+static ATTEMPTS: OnceLock<Mutex<HashMap<IpAddr, Attempts>>> = OnceLock::new();
+
+/// Failed logins from a single address.
+struct Attempts {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Returns how long the address is still locked out for, if it is.
+pub fn check(address: IpAddr) -> Result<(), Duration> {
+    let config = &config::get().auth.lockout;
+
+    if !config.enabled {
+        return Ok(());
+    }
+
+    let attempts = attempts().lock().unwrap();
+    let now = Instant::now();
+
+    match attempts.get(&address).and_then(|attempts| attempts.locked_until) {
+        Some(locked_until) if locked_until > now => Err(locked_until - now),
+        _ => Ok(()),
+    }
+}
+
+/// Counts a failed login, and locks the address out once it has failed
+/// too often. Every failure after that doubles the lockout.
+pub fn record_failure(address: IpAddr, identity: &str) {
+    let config = &config::get().auth.lockout;
+
+    if !config.enabled {
+        return;
+    }
+
+    let mut attempts = attempts().lock().unwrap();
+    let now = Instant::now();
+
+    if !attempts.contains_key(&address) {
+        make_room(&mut attempts, config, now);
+    }
+
+    let entry = attempts.entry(address).or_insert(Attempts {
+        failures: 0,
+        last_failure: now,
+        locked_until: None,
+    });
+
+    if entry.is_expired(config, now) {
+        entry.failures = 0;
+    }
+
+    entry.failures += 1;
+    entry.last_failure = now;
+
+    println!("Failed login for {identity} from {address} ({} in a row)", entry.failures);
+
+    if entry.failures >= config.max_failures {
+        let lockout = lockout_time(config, entry.failures);
+        entry.locked_until = Some(now + lockout);
+
+        println!(
+            "Locked out {address} for {} seconds after {} failed logins",
+            lockout.as_secs(),
+            entry.failures
+        );
+    }
+}
+
+pub fn record_success(address: IpAddr) {
+    if !config::get().auth.lockout.enabled {
+        return;
+    }
+
+    attempts().lock().unwrap().remove(&address);
+}
+
+impl Attempts {
+    /// Whether the failures can be forgotten, because the lockout is over
+    /// and there haven't been any new ones for a while.
+    fn is_expired(&self, config: &LockoutConfig, now: Instant) -> bool {
+        let locked = self.locked_until.is_some_and(|locked_until| locked_until > now);
+        let reset_after = Duration::from_secs(config.reset_after);
+
+        !locked && now.duration_since(self.last_failure) >= reset_after
+    }
+}
+
+fn attempts() -> &'static Mutex<HashMap<IpAddr, Attempts>> {
+    ATTEMPTS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn lockout_time(config: &LockoutConfig, failures: u32) -> Duration {
+    let doublings = (failures - config.max_failures).min(32);
+    let seconds = config.lockout_time.saturating_mul(1 << doublings);
+
+    Duration::from_secs(seconds.min(config.max_lockout_time))
+}
+
+/// Keeps memory use bounded: forgets expired entries first, and then
+/// the address that failed least recently.
+fn make_room(attempts: &mut HashMap<IpAddr, Attempts>, config: &LockoutConfig, now: Instant) {
+    if attempts.len() < config.max_tracked_addresses {
+        return;
+    }
+
+    attempts.retain(|_, attempts| !attempts.is_expired(config, now));
+
+    while attempts.len() >= config.max_tracked_addresses {
+        let oldest = attempts
+            .iter()
+            .min_by_key(|(_, attempts)| attempts.last_failure)
+            .map(|(address, _)| *address);
+
+        match oldest {
+            Some(address) => attempts.remove(&address),
+            None => break,
+        };
+    }
+}
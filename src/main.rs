@@ -1,32 +1,59 @@
-use hyper::server::conn::http1;
 use hyper::service::service_fn;
-use hyper_util::rt::TokioIo;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful::{GracefulShutdown, Watcher};
 use std::convert::Infallible;
-use tokio::net::TcpListener;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::TlsAcceptor;
 
-use http_body_util::Full;
-use hyper::body::Bytes;
+use body::Body;
 use hyper::header::HeaderValue;
 use hyper::{Method, Request, Response};
 
-type Resp = Response<Full<Bytes>>;
+type Resp = Response<Body>;
 type Req = Request<hyper::body::Incoming>;
 
+mod access_log;
 mod auth;
+mod body;
 mod config;
+mod credentials;
+mod deletions;
+mod fancy_urls;
 mod files;
+mod htmd;
+mod idle;
+mod limits;
+mod lockout;
+mod permissions;
+mod redirects;
+mod reload;
 mod response;
+mod tls;
+mod tokens;
 mod uploads;
 mod content_encoding;
 
 use clap::Parser;
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Address for the server to run on
-    #[arg(short, long, default_value_t = String::from("127.0.0.1:4000"))]
-    address: String,
+    /// Path to the config file [default: ~/.config/waiter/config.toml]
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Address for the server to run on [default: 127.0.0.1:4000]
+    #[arg(short, long)]
+    address: Option<String>,
+
+    /// Directory to serve [default: .]
+    #[arg(short, long)]
+    scope: Option<String>,
 
     /// Disable cache control and content encoding
     #[arg(short, long, default_value_t = false)]
@@ -36,46 +63,301 @@ struct Args {
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let args = Args::parse();
-    println!("Now listening on {}", args.address);
 
-    let listener = TcpListener::bind(args.address).await?;
+    let config = match load_config(args) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{error}");
+            std::process::exit(1);
+        }
+    };
+
+    config::init(config);
+    let config = config::get();
+
+    if let Err(error) = access_log::init(&config.access_log).and_then(|_| access_log::reopen_on_sighup()) {
+        eprintln!("{error}");
+        std::process::exit(1);
+    }
+
+    if let Err(error) = credentials::init(&config.auth)
+        .and_then(|_| tokens::init(&config.auth))
+        .and_then(|_| permissions::init(&config.permissions))
+        .and_then(|_| limits::init(&config.limits))
+        .and_then(|_| fancy_urls::init(&config.fancy_urls))
+        .and_then(|_| redirects::init(config.redirects_file.as_deref())) {
+        eprintln!("{error}");
+        std::process::exit(1);
+    }
+
+    if !config.auth.has_credentials() {
+        println!("No credentials configured; `PUT` and `DELETE` requests will be refused");
+    }
+
+    let listener = TcpListener::bind(&config.address).await?;
+    println!("Now listening on http://{}", config.address);
+
+    let tls = match &config.tls {
+        Some(tls) => {
+            let acceptor = match tls::build_acceptor(tls, config.http2.enabled) {
+                Ok(acceptor) => acceptor,
+                Err(error) => {
+                    eprintln!("{error}");
+                    std::process::exit(1);
+                }
+            };
+
+            let tls_listener = TcpListener::bind(&tls.address).await?;
+            println!("Now listening on https://{}", tls.address);
+
+            Some((tls_listener, acceptor))
+        }
+        None => None,
+    };
+
+    let graceful = GracefulShutdown::new();
+    let mut shutdown = std::pin::pin!(shutdown_signal());
 
     loop {
-        let (stream, _) = listener.accept().await?;
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, remote_addr) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        back_off_after_accept_error(err).await;
+                        continue;
+                    }
+                };
+
+                let connection = match limits::acquire_connection(remote_addr.ip()) {
+                    Some(connection) => connection,
+                    None => continue,
+                };
+
+                let watcher = graceful.watcher();
+
+                tokio::task::spawn(async move {
+                    serve_connection(TokioIo::new(stream), remote_addr, false, watcher).await;
+                    drop(connection);
+                });
+            }
+            accepted = accept_tls_connection(&tls) => {
+                let (stream, remote_addr) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        back_off_after_accept_error(err).await;
+                        continue;
+                    }
+                };
+
+                let connection = match limits::acquire_connection(remote_addr.ip()) {
+                    Some(connection) => connection,
+                    None => continue,
+                };
+
+                let acceptor = tls.as_ref().unwrap().1.clone();
+                let watcher = graceful.watcher();
+                let handshake_timeout = Duration::from_secs(config.tls.as_ref().unwrap().handshake_timeout);
+
+                tokio::task::spawn(async move {
+                    // The connection counts towards the limits already, so
+                    // a stalled handshake mustn't keep it forever.
+                    match tokio::time::timeout(handshake_timeout, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => serve_connection(TokioIo::new(stream), remote_addr, true, watcher).await,
+                        Ok(Err(err)) => println!("Error during TLS handshake: {:?}", err),
+                        Err(_) => println!("TLS handshake with {remote_addr} timed out"),
+                    }
 
-        let io = TokioIo::new(stream);
+                    drop(connection);
+                });
+            }
+            _ = &mut shutdown => break,
+        }
+    }
+
+    // Stop accepting new connections, and give the open ones some time
+    // to finish what they're doing.
+    drop(listener);
+    drop(tls);
+
+    println!("Shutting down; waiting for {} connection(s) to finish", graceful.count());
+
+    let timeout = Duration::from_secs(config.shutdown_timeout);
+
+    if tokio::time::timeout(timeout, graceful.shutdown()).await.is_err() {
+        println!("Timed out after {} seconds; aborting remaining connections", config.shutdown_timeout);
+    }
+
+    Ok(())
+}
+
+/// Accepting fails when we run out of file descriptors, for example.
+/// Retrying right away would only spin, so wait for connections to close.
+async fn back_off_after_accept_error(err: std::io::Error) {
+    println!("Error accepting connection: {:?}", err);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+}
 
-        tokio::task::spawn(async move {
-            if let Err(err) = http1::Builder::new()
-                .serve_connection(io, service_fn(move |req| handle_request(req, args.dev)))
-                .await
-            {
+/// Never resolves if TLS is disabled, so it can be used in `select!`.
+async fn accept_tls_connection(
+    tls: &Option<(TcpListener, TlsAcceptor)>,
+) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match tls {
+        Some((listener, _)) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Should be able to listen for SIGTERM!");
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = terminate.recv() => (),
+    }
+}
+
+async fn serve_connection<I>(io: I, remote_addr: SocketAddr, https: bool, watcher: Watcher)
+where
+    I: hyper::rt::Read + hyper::rt::Write + Unpin + Send + 'static,
+{
+    let config = config::get();
+    let dev_mode = config.dev;
+
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+
+    if config.limits.header_read_timeout > 0 {
+        builder
+            .http1()
+            .timer(TokioTimer::new())
+            .header_read_timeout(Duration::from_secs(config.limits.header_read_timeout));
+    } else {
+        builder.http1().header_read_timeout(None);
+    }
+
+    if config.http2.enabled {
+        builder
+            .http2()
+            .max_concurrent_streams(config.http2.max_concurrent_streams)
+            .initial_stream_window_size(config.http2.initial_stream_window_size)
+            .initial_connection_window_size(config.http2.initial_connection_window_size)
+            .max_header_list_size(config.http2.max_header_list_size);
+    } else {
+        builder = builder.http1_only();
+    }
+
+    // Also covers what hyper's own timeouts don't, like clients that
+    // connect and then never send a byte.
+    let (io, activity) = idle::track(io);
+
+    let connection = builder
+        .serve_connection(io, service_fn(move |req| handle_connection_request(req, remote_addr, https, dev_mode)))
+        .into_owned();
+
+    let idle_timeout = async {
+        match config.limits.idle_timeout {
+            0 => std::future::pending().await,
+            seconds => activity.idle_for(Duration::from_secs(seconds)).await,
+        }
+    };
+
+    tokio::select! {
+        result = watcher.watch(connection) => {
+            if let Err(err) = result {
                 println!("Error serving connection: {:?}", err);
             }
-        });
+        }
+        // Dropping the connection closes it.
+        _ = idle_timeout => (),
     }
 }
 
-async fn handle_request(request: Req, dev_mode: bool) -> Result<Resp, Infallible> {
+/// Requests over the rate limit are turned away, and plain HTTP requests
+/// are redirected to HTTPS, if configured. Other errors get the site's
+/// own error page, if it has one.
+async fn handle_connection_request(
+    request: Req,
+    remote_addr: SocketAddr,
+    https: bool,
+    dev_mode: bool,
+) -> Result<Resp, Infallible> {
+    let log_entry = access_log::Entry::new(&request, remote_addr);
+
+    // Rejected before touching the disk at all, error pages included, so
+    // the limiter actually sheds load.
+    if let Err(retry_after) = limits::check_rate(remote_addr.ip(), request.uri().path()) {
+        let response = response::serve_429("Too many requests; slow down.", retry_after);
+        return Ok(access_log::attach(response, log_entry));
+    }
+
+    let request_head = response::error_pages::snapshot(&request);
+
+    let response = match &config::get().tls {
+        Some(tls) if tls.redirect_http && !https => tls::redirect_to_https(&request, tls),
+        _ => handle_request(request, remote_addr, dev_mode).await?,
+    };
+
+    let response = response::error_pages::apply(response, &request_head).await;
+
+    Ok(access_log::attach(response, log_entry))
+}
+
+fn load_config(args: Args) -> Result<config::Config, String> {
+    let mut config = config::load(args.config.as_deref())?;
+
+    if let Some(address) = args.address {
+        config.address = address;
+    }
+
+    if let Some(scope) = args.scope {
+        config.scope = scope;
+    }
+
+    config.dev |= args.dev;
+
+    config.validate()?;
+    Ok(config)
+}
+
+async fn handle_request(request: Req, remote_addr: SocketAddr, dev_mode: bool) -> Result<Resp, Infallible> {
     match *request.method() {
-        Method::PUT => handle_put_request(request, dev_mode).await,
-        Method::GET => handle_get_request(request, dev_mode).await,
+        Method::PUT => handle_put_request(request, remote_addr, dev_mode).await,
+        Method::DELETE => handle_delete_request(request, remote_addr, dev_mode).await,
+        Method::GET | Method::HEAD => handle_get_request(request, dev_mode).await,
         _ => Ok(response::serve(
             400,
-            "Bad request; only `GET` and `PUT` requests are supported.",
+            "Bad request; only `GET`, `HEAD`, `PUT` and `DELETE` requests are supported.",
         )),
     }
 }
 
-async fn handle_put_request(request: Req, _dev_mode: bool) -> Result<Resp, Infallible> {
-    match auth::require_authentication(request) {
+async fn handle_put_request(request: Req, remote_addr: SocketAddr, _dev_mode: bool) -> Result<Resp, Infallible> {
+    match auth::require_permission(request, remote_addr.ip()).await {
         Ok(request) => uploads::process_put_request(request).await,
         Err(response) => Ok(response),
     }
 }
 
+async fn handle_delete_request(request: Req, remote_addr: SocketAddr, _dev_mode: bool) -> Result<Resp, Infallible> {
+    match auth::require_permission(request, remote_addr.ip()).await {
+        Ok(request) => deletions::process_delete_request(request).await,
+        Err(response) => Ok(response),
+    }
+}
+
 async fn handle_get_request(request: Req, dev_mode: bool) -> Result<Resp, Infallible> {
-    let mut response = response::try_files(&request).await;
+    let request = match redirects::apply(request) {
+        Ok(request) => request,
+        Err(response) => return Ok(response),
+    };
+
+    let encodings = if dev_mode {
+        Vec::new()
+    } else {
+        content_encoding::accepted_encodings(&request)
+    };
+
+    let mut response = response::try_files(&request, &encodings).await;
     let url = request.uri();
 
     set_additional_headers(&mut response);
@@ -101,10 +383,12 @@ fn set_cache_time(response: &mut Resp, request_url: &str) {
 }
 
 fn get_cache_time_for_filetype(filename: &str) -> u64 {
+    let config = config::get();
+
     if is_static_asset(filename) {
-        config::CACHE_TIME_ASSETS
+        config.cache.assets
     } else {
-        config::CACHE_TIME_CONTENT
+        config.cache.content
     }
 }
 
@@ -119,17 +403,24 @@ fn is_static_asset(filename: &str) -> bool {
 }
 
 fn set_additional_headers(response: &mut Resp) {
+    let config = config::get();
     let headers = response.headers_mut();
 
-    headers.insert("Server", HeaderValue::from_static(config::SERVER_NAME));
+    headers.insert("Server", HeaderValue::from_str(&config.server_name).unwrap());
     headers.insert(
         "Content-Language",
-        HeaderValue::from_static(config::CONTENT_LANGUAGE),
+        HeaderValue::from_str(&config.content_language).unwrap(),
     );
 }
 
 fn maybe_correct_content_type(response: &mut Resp, request: &Req) {
-    if serving_htmd_file(response) && !accepts_htmd_mime_type(request) {
+    if !serving_htmd_file(response) {
+        return;
+    }
+
+    response::negotiation::add_vary_accept(response);
+
+    if !accepts_htmd_mime_type(request) {
         let headers = response.headers_mut();
 
         headers.insert("Content-Type", HeaderValue::from_static("text/plain"));
@@ -149,13 +440,5 @@ fn serving_htmd_file(response: &mut Resp) -> bool {
 }
 
 fn accepts_htmd_mime_type(request: &Req) -> bool {
-    let default_header = HeaderValue::from_static("*/*");
-
-    request
-        .headers()
-        .get("Accept")
-        .unwrap_or(&default_header)
-        .to_str()
-        .expect("Accept header in request should be a string!")
-        .contains("text/htmd")
+    response::negotiation::accepts_explicitly(request, "text/htmd")
 }
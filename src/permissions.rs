@@ -0,0 +1,209 @@
+use crate::config::{PermissionsConfig, RuleEffect};
+use crate::files;
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use globset::{Glob, GlobBuilder, GlobMatcher};
+use hyper::Method;
+
+static RULES: OnceLock<Vec<Rule>> = OnceLock::new();
+
+/// A rule from the config, with its paths compiled.
+struct Rule {
+    name: String,
+    effect: RuleEffect,
+    /// `None` means the rule applies to every user.
+    users: Option<Vec<String>>,
+    paths: Vec<PathPattern>,
+    methods: Vec<Method>,
+}
+
+pub enum PathPattern {
+    Prefix(String),
+    Glob(GlobMatcher),
+}
+
+/// Compiles the rules from the config. Without any rules, every
+/// authenticated user may publish anywhere in scope.
+pub fn init(config: &PermissionsConfig) -> Result<(), String> {
+    let rules = config
+        .rules
+        .iter()
+        .map(|rule| {
+            let users = match (&rule.users, &rule.groups) {
+                (None, None) => None,
+                (users, groups) => Some(expand_groups(
+                    users.as_deref().unwrap_or_default(),
+                    groups.as_deref().unwrap_or_default(),
+                    &config.groups,
+                    &rule.name,
+                )?),
+            };
+
+            let paths = rule
+                .paths
+                .iter()
+                .map(|path| compile_path_pattern(path))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|error| format!("Invalid permissions rule `{}`: {error}", rule.name))?;
+
+            Ok(Rule {
+                name: rule.name.clone(),
+                effect: rule.effect,
+                users,
+                paths,
+                methods: rule.methods.iter().map(|method| method.to_method()).collect(),
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    if RULES.set(rules).is_err() {
+        return Err(String::from("Permissions should only be initialized once!"));
+    }
+
+    Ok(())
+}
+
+/// Checks the rules in order; the first one that matches decides. If
+/// access is denied, the error explains which rule denied it.
+pub fn authorize(username: &str, method: &Method, path: &str) -> Result<(), String> {
+    let rules = match RULES.get() {
+        Some(rules) if !rules.is_empty() => rules,
+        _ => return Ok(()),
+    };
+
+    let path = normalize_request_path(path);
+
+    let matching_rule = rules.iter().find(|rule| {
+        rule.applies_to_user(username)
+            && rule.methods.contains(method)
+            && rule.paths.iter().any(|pattern| pattern.matches(&path))
+    });
+
+    match matching_rule {
+        Some(rule) if rule.effect == RuleEffect::Allow => Ok(()),
+        Some(rule) => Err(format!(
+            "Forbidden; `{method}` on {path} is denied for {username} by rule `{}`.",
+            rule.name
+        )),
+        None => Err(format!(
+            "Forbidden; no rule allows {username} to `{method}` {path}, so it's denied by default."
+        )),
+    }
+}
+
+impl Rule {
+    fn applies_to_user(&self, username: &str) -> bool {
+        match &self.users {
+            Some(users) => users.iter().any(|user| user == username),
+            None => true,
+        }
+    }
+}
+
+impl PathPattern {
+    pub fn matches(&self, path: &str) -> bool {
+        match self {
+            // Whole segments only, so `/blog/alice` doesn't cover
+            // `/blog/alice-old`.
+            PathPattern::Prefix(prefix) => match path.strip_prefix(prefix.as_str()) {
+                Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'),
+                None => false,
+            },
+            PathPattern::Glob(glob) => glob.is_match(path),
+        }
+    }
+}
+
+/// Patterns containing `*`, `?`, `[` or `{` are globs (where `*` stays
+/// within a single directory and `**` crosses them). Anything else is
+/// matched as a prefix of whole path segments.
+pub fn compile_path_pattern(pattern: &str) -> Result<PathPattern, String> {
+    if !pattern.starts_with('/') {
+        return Err(format!("path `{pattern}` should start with `/`."));
+    }
+
+    if !pattern.contains(['*', '?', '[', '{']) {
+        return Ok(PathPattern::Prefix(pattern.to_string()));
+    }
+
+    let glob: Glob = GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()
+        .map_err(|error| format!("invalid glob `{pattern}`: {error}"))?;
+
+    Ok(PathPattern::Glob(glob.compile_matcher()))
+}
+
+/// Resolves `.` and `..` so they can't be used to sneak past a rule.
+pub fn normalize_request_path(path: &str) -> String {
+    match files::normalize_path(Path::new("/"), path) {
+        Some(path) => path.to_string_lossy().into_owned(),
+        None => String::from("/"),
+    }
+}
+
+fn expand_groups(
+    users: &[String],
+    groups: &[String],
+    defined_groups: &HashMap<String, Vec<String>>,
+    rule_name: &str,
+) -> Result<Vec<String>, String> {
+    let mut expanded = users.to_vec();
+
+    for group in groups {
+        let members = defined_groups.get(group).ok_or_else(|| {
+            format!("Invalid permissions rule `{rule_name}`: unknown group `{group}`.")
+        })?;
+
+        expanded.extend(members.iter().cloned());
+    }
+
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        compile_path_pattern(pattern).unwrap().matches(path)
+    }
+
+    #[test]
+    fn prefixes_match_whole_segments() {
+        assert!(matches("/blog/alice", "/blog/alice"));
+        assert!(matches("/blog/alice", "/blog/alice/post.htmd"));
+        assert!(!matches("/blog/alice", "/blog/alice-evil/pwn.htmd"));
+        assert!(!matches("/blog/alice", "/blog/alic"));
+
+        assert!(matches("/blog/alice/", "/blog/alice/post.htmd"));
+        assert!(!matches("/blog/alice/", "/blog/alice-evil/pwn.htmd"));
+        assert!(matches("/", "/anything/at/all"));
+    }
+
+    #[test]
+    fn globs_respect_directories() {
+        assert!(matches("/blog/*/drafts/**", "/blog/alice/drafts/a/b.htmd"));
+        assert!(!matches("/blog/*/drafts/**", "/blog/alice/bob/drafts/a.htmd"));
+        assert!(matches("/pages/*.htmd", "/pages/about.htmd"));
+        assert!(!matches("/pages/*.htmd", "/pages/sub/about.htmd"));
+        assert!(matches("/{a,b}/?.txt", "/b/x.txt"));
+    }
+
+    #[test]
+    fn patterns_must_be_absolute_and_valid() {
+        assert!(compile_path_pattern("blog/").is_err());
+        assert!(compile_path_pattern("/blog/[").is_err());
+    }
+
+    #[test]
+    fn normalizes_request_paths() {
+        assert_eq!(normalize_request_path("/blog/alice/../bob/x"), "/blog/bob/x");
+        assert_eq!(normalize_request_path("/blog/./alice/"), "/blog/alice");
+        assert_eq!(normalize_request_path("/blog/%2e%2e/admin"), "/admin");
+        assert_eq!(normalize_request_path("/../../etc"), "/");
+    }
+}
@@ -0,0 +1,494 @@
+use crate::body::{self, Body};
+use crate::reload;
+
+use std::path::Path;
+use std::sync::{OnceLock, RwLock};
+
+use hyper::header::HeaderValue;
+use hyper::http::uri::PathAndQuery;
+use hyper::{Request, Response, Uri};
+use regex::Regex;
+use serde::Deserialize;
+
+type Resp = Response<Body>;
+type Req = Request<hyper::body::Incoming>;
+
+static RULES: OnceLock<RwLock<Vec<Rule>>> = OnceLock::new();
+
+/// Redirects longer than this are treated as loops.
+const MAX_HOPS: usize = 10;
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct RedirectsFile {
+    #[serde(default)]
+    redirects: Vec<RuleConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    #[serde(default, rename = "match")]
+    kind: MatchKind,
+
+    from: String,
+
+    /// For prefixes, the rest of the path is appended. Globs and regexes
+    /// can refer to what they captured using `$1`, `$2`, etc.
+    to: String,
+
+    #[serde(default = "default_status")]
+    status: u16,
+
+    /// Serve `to` in place of `from`, without telling the client
+    #[serde(default)]
+    rewrite: bool,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum MatchKind {
+    #[default]
+    Exact,
+    Prefix,
+    Glob,
+    Regex,
+}
+
+struct Rule {
+    kind: MatchKind,
+    from: String,
+    pattern: Pattern,
+    to: String,
+    action: Action,
+}
+
+enum Pattern {
+    Exact(String),
+    Prefix(String),
+    /// Globs are compiled to regexes too, so they can capture.
+    Regex(Regex),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Redirect(u16),
+    Rewrite,
+}
+
+fn default_status() -> u16 {
+    301
+}
+
+pub fn init(redirects_file: Option<&Path>) -> Result<(), String> {
+    let path = match redirects_file {
+        Some(path) => path.to_path_buf(),
+        None => return Ok(()),
+    };
+
+    let rules = load_redirects_file(&path)?;
+
+    if RULES.set(RwLock::new(rules)).is_err() {
+        return Err(String::from("Redirects should only be initialized once!"));
+    }
+
+    reload::watch_files(vec![path.clone()], move || {
+        let rules = load_redirects_file(&path)?;
+        let count = rules.len();
+
+        *RULES.get().unwrap().write().unwrap() = rules;
+
+        println!("Reloaded {count} redirect(s) from {}", path.display());
+        Ok(())
+    });
+
+    Ok(())
+}
+
+/// Checks the request against the rules; the first one that matches
+/// decides. Returns the request, rewritten if needed, or the redirect.
+#[allow(clippy::result_large_err)]
+pub fn apply(mut request: Req) -> Result<Req, Resp> {
+    let rules = match RULES.get() {
+        Some(rules) => rules.read().unwrap(),
+        None => return Ok(request),
+    };
+
+    let (rule, target) = match resolve_uri(&rules, request.uri()) {
+        Some(resolved) => resolved,
+        None => return Ok(request),
+    };
+
+    match rule.action {
+        Action::Redirect(status) => match HeaderValue::from_str(&target) {
+            Ok(location) => Err(Response::builder()
+                .status(status)
+                .header("Location", location)
+                .body(body::empty())
+                .unwrap()),
+            Err(_) => {
+                eprintln!("Invalid redirect from {} to {target}", request.uri().path());
+                Ok(request)
+            }
+        },
+        Action::Rewrite => {
+            match rewrite_uri(request.uri(), &target) {
+                Some(uri) => *request.uri_mut() = uri,
+                None => eprintln!("Invalid rewrite from {} to {target}", request.uri().path()),
+            }
+
+            Ok(request)
+        }
+    }
+}
+
+/// Like `resolve`, but keeps the query string, unless the rule sets one
+/// of its own.
+fn resolve_uri<'a>(rules: &'a [Rule], uri: &Uri) -> Option<(&'a Rule, String)> {
+    let (rule, target) = resolve(rules, uri.path())?;
+
+    let target = match uri.query() {
+        Some(query) if !target.contains('?') => format!("{target}?{query}"),
+        _ => target,
+    };
+
+    Some((rule, target))
+}
+
+fn resolve<'a>(rules: &'a [Rule], path: &str) -> Option<(&'a Rule, String)> {
+    rules
+        .iter()
+        .find_map(|rule| rule.target(path).map(|target| (rule, target)))
+}
+
+impl Rule {
+    fn target(&self, path: &str) -> Option<String> {
+        match &self.pattern {
+            Pattern::Exact(from) => (path == from).then(|| self.to.clone()),
+            Pattern::Prefix(from) => path.strip_prefix(from.as_str()).map(|rest| format!("{}{rest}", self.to)),
+            Pattern::Regex(regex) => regex.captures(path).map(|captures| {
+                let mut target = String::new();
+                captures.expand(&self.to, &mut target);
+                target
+            }),
+        }
+    }
+
+    /// A path this rule matches, to check for loops with. There's no
+    /// telling what a regex matches in general, though.
+    fn sample_path(&self) -> Option<String> {
+        match self.kind {
+            MatchKind::Exact | MatchKind::Prefix => Some(self.from.clone()),
+            MatchKind::Glob => Some(self.from.replace("**", "x").replace(['*', '?'], "x")),
+            MatchKind::Regex => None,
+        }
+    }
+
+    /// The target with its captures filled in, in a few different ways.
+    /// Any loop passes through the target of one of its rules, so these
+    /// catch loops between regexes too.
+    fn sample_targets(&self) -> Vec<String> {
+        let references = Regex::new(r"\$(\$|\{[^}]*\}|[0-9A-Za-z_]+)").unwrap();
+        let path = self.to.split('?').next().unwrap_or_default();
+
+        let mut samples: Vec<String> = ["x", "0", ""]
+            .into_iter()
+            .map(|filler| {
+                let sample = references.replace_all(path, |captures: &regex::Captures| match &captures[1] {
+                    "$" => String::from("$"),
+                    _ => filler.to_string(),
+                });
+
+                sample.into_owned()
+            })
+            .collect();
+
+        samples.dedup();
+        samples
+    }
+}
+
+/// Replaces the path (and query) of the URI, keeping the rest.
+fn rewrite_uri(uri: &Uri, target: &str) -> Option<Uri> {
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(target.parse::<PathAndQuery>().ok()?);
+
+    Uri::from_parts(parts).ok()
+}
+
+fn load_redirects_file(path: &Path) -> Result<Vec<Rule>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|error| format!("Couldn't read redirects file {}: {error}", path.display()))?;
+
+    parse_rules(&contents).map_err(|error| format!("Invalid redirects file {}: {error}", path.display()))
+}
+
+fn parse_rules(contents: &str) -> Result<Vec<Rule>, String> {
+    let file: RedirectsFile = toml::from_str(contents).map_err(|error| error.to_string())?;
+
+    let rules = file
+        .redirects
+        .into_iter()
+        .map(compile_rule)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    check_for_loops(&rules)?;
+
+    Ok(rules)
+}
+
+fn compile_rule(rule: RuleConfig) -> Result<Rule, String> {
+    if !rule.from.starts_with('/') && rule.kind != MatchKind::Regex {
+        return Err(format!("`{}` should start with `/`.", rule.from));
+    }
+
+    let action = match (rule.rewrite, rule.status) {
+        (true, _) if !rule.to.starts_with('/') => {
+            return Err(format!("rewrite to `{}` should be a path starting with `/`.", rule.to));
+        }
+        (true, _) => Action::Rewrite,
+        (false, status @ (301 | 302 | 307 | 308)) => Action::Redirect(status),
+        (false, status) => {
+            return Err(format!("status {status} for `{}` should be 301, 302, 307 or 308.", rule.from));
+        }
+    };
+
+    let pattern = match rule.kind {
+        MatchKind::Exact => Pattern::Exact(rule.from.clone()),
+        MatchKind::Prefix => Pattern::Prefix(rule.from.clone()),
+        MatchKind::Glob => Pattern::Regex(compile_regex(&glob_to_regex(&rule.from))?),
+        // Regexes must match the whole path, like the other patterns.
+        MatchKind::Regex => Pattern::Regex(compile_regex(&format!("^(?:{})$", rule.from))?),
+    };
+
+    Ok(Rule {
+        kind: rule.kind,
+        from: rule.from,
+        pattern,
+        to: rule.to,
+        action,
+    })
+}
+
+fn compile_regex(pattern: &str) -> Result<Regex, String> {
+    Regex::new(pattern).map_err(|error| format!("invalid pattern `{pattern}`: {error}"))
+}
+
+/// `*` matches within a single directory, `**` across them, and `?` a
+/// single character. Each of them is captured.
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str("(.*)");
+            }
+            '*' => regex.push_str("([^/]*)"),
+            '?' => regex.push_str("([^/])"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    regex.push('$');
+    regex
+}
+
+/// Follows the redirects from every rule and every target the way a
+/// client would, and fails if it ends up where it's been before or takes
+/// too many hops. Rewrites are only applied once, so they can't loop.
+fn check_for_loops(rules: &[Rule]) -> Result<(), String> {
+    let starts = rules
+        .iter()
+        .filter_map(Rule::sample_path)
+        .chain(rules.iter().flat_map(Rule::sample_targets))
+        .filter(|path| path.starts_with('/'));
+
+    for mut path in starts {
+        let mut chain = vec![path.clone()];
+
+        while let Some((rule, target)) = resolve(rules, &path) {
+            // External redirects and rewrites end the chain.
+            if rule.action == Action::Rewrite || !target.starts_with('/') {
+                break;
+            }
+
+            let target = target.split('?').next().unwrap_or_default().to_string();
+            let looped = chain.contains(&target);
+
+            chain.push(target.clone());
+
+            if looped {
+                return Err(format!("redirect loop: {}.", chain.join(" -> ")));
+            }
+
+            if chain.len() > MAX_HOPS {
+                return Err(format!("too many redirects in a row: {} -> ...", chain.join(" -> ")));
+            }
+
+            path = target;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(rules: &str, uri: &str) -> Option<String> {
+        let rules = parse_rules(rules).unwrap();
+        resolve_uri(&rules, &uri.parse().unwrap()).map(|(_, target)| target)
+    }
+
+    #[test]
+    fn globs_capture_their_wildcards() {
+        let regex = Regex::new(&glob_to_regex("/posts/*/**.html")).unwrap();
+        let captures = regex.captures("/posts/2024/a/b.html").unwrap();
+
+        assert_eq!(&captures[1], "2024");
+        assert_eq!(&captures[2], "a/b");
+        assert!(!regex.is_match("/posts/2024.html"));
+
+        let regex = Regex::new(&glob_to_regex("/a.b/?")).unwrap();
+        assert!(regex.is_match("/a.b/x"));
+        assert!(!regex.is_match("/aXb/x"));
+        assert!(!regex.is_match("/a.b/xy"));
+    }
+
+    #[test]
+    fn glob_targets_refer_to_captures() {
+        let rules = r#"
+            [[redirects]]
+            match = "glob"
+            from = "/posts/*/*.html"
+            to = "/blog/${2}?year=${1}"
+        "#;
+
+        assert_eq!(target(rules, "/posts/2024/hello.html").as_deref(), Some("/blog/hello?year=2024"));
+        assert_eq!(target(rules, "/posts/2024/a/hello.html"), None);
+    }
+
+    #[test]
+    fn prefixes_append_the_rest() {
+        let rules = r#"
+            [[redirects]]
+            match = "prefix"
+            from = "/archive/"
+            to = "/blog/"
+        "#;
+
+        assert_eq!(target(rules, "/archive/2024/post").as_deref(), Some("/blog/2024/post"));
+        assert_eq!(target(rules, "/archive/").as_deref(), Some("/blog/"));
+        assert_eq!(target(rules, "/archived"), None);
+    }
+
+    #[test]
+    fn keeps_the_query_unless_the_target_has_one() {
+        let rules = r#"
+            [[redirects]]
+            from = "/old"
+            to = "/new"
+
+            [[redirects]]
+            from = "/search"
+            to = "/find?q=all"
+        "#;
+
+        assert_eq!(target(rules, "/old?page=2").as_deref(), Some("/new?page=2"));
+        assert_eq!(target(rules, "/old").as_deref(), Some("/new"));
+        assert_eq!(target(rules, "/search?q=x").as_deref(), Some("/find?q=all"));
+    }
+
+    #[test]
+    fn regexes_must_match_the_whole_path() {
+        let rules = r#"
+            [[redirects]]
+            match = "regex"
+            from = "/p/(?P<id>[0-9]+)"
+            to = "/blog/by-id/${id}"
+        "#;
+
+        assert_eq!(target(rules, "/p/42").as_deref(), Some("/blog/by-id/42"));
+        assert_eq!(target(rules, "/p/42/x"), None);
+        assert_eq!(target(rules, "/x/p/42"), None);
+    }
+
+    #[test]
+    fn rejects_loops() {
+        let exact = r#"
+            [[redirects]]
+            from = "/a"
+            to = "/b"
+
+            [[redirects]]
+            from = "/b"
+            to = "/a"
+        "#;
+
+        let own_regex = r#"
+            [[redirects]]
+            match = "regex"
+            from = "/a(.*)"
+            to = "/a${1}"
+        "#;
+
+        let mutual_regexes = r#"
+            [[redirects]]
+            match = "regex"
+            from = "/p/(.*)"
+            to = "/q/${1}"
+
+            [[redirects]]
+            match = "regex"
+            from = "/q/(.*)"
+            to = "/p/${1}"
+        "#;
+
+        let glob_and_regex = r#"
+            [[redirects]]
+            match = "glob"
+            from = "/old/*"
+            to = "/new/${1}"
+
+            [[redirects]]
+            match = "regex"
+            from = "/new/([a-z]+)"
+            to = "/old/${1}"
+        "#;
+
+        for rules in [exact, own_regex, mutual_regexes, glob_and_regex] {
+            let error = parse_rules(rules).err().unwrap();
+            assert!(error.starts_with("redirect loop"), "{error}");
+        }
+    }
+
+    #[test]
+    fn allows_chains_and_rewrites() {
+        let rules = r#"
+            [[redirects]]
+            from = "/a"
+            to = "/b"
+
+            [[redirects]]
+            match = "prefix"
+            from = "/b"
+            to = "/c/"
+
+            [[redirects]]
+            match = "regex"
+            from = "/c/(.*)"
+            to = "https://example.com/${1}"
+
+            [[redirects]]
+            from = "/r"
+            to = "/r"
+            rewrite = true
+        "#;
+
+        assert!(parse_rules(rules).is_ok());
+    }
+}
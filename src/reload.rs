@@ -0,0 +1,40 @@
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// How often watched files are checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Calls `on_change` whenever one of `paths` is modified on disk. If
+/// `on_change` fails, it's retried on the next poll, eg. to deal with
+/// files that are only partially written.
+pub fn watch_files<F>(paths: Vec<PathBuf>, on_change: F)
+where
+    F: Fn() -> Result<(), String> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut last_seen = modification_times(&paths);
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let current = modification_times(&paths);
+
+            if current == last_seen {
+                continue;
+            }
+
+            match on_change() {
+                Ok(()) => last_seen = current,
+                Err(error) => println!("{error}"),
+            }
+        }
+    });
+}
+
+fn modification_times(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| path.metadata().and_then(|metadata| metadata.modified()).ok())
+        .collect()
+}
@@ -1,92 +1,396 @@
+use crate::body::{self, Body};
 use crate::config;
+use crate::content_encoding::Encoding;
+use crate::fancy_urls::{self, Fallback};
 use crate::files;
+pub mod autoindex;
+pub mod error_pages;
 pub mod mime;
+pub mod negotiation;
+pub mod render;
+pub mod ranges;
+pub mod validators;
 
-use std::path::Path;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use http_body_util::Full;
-use hyper::body::Bytes;
-use hyper::{Request, Response};
+use ranges::{ByteRange, RangeRequest};
+use validators::Validators;
 
-type Resp = Response<Full<Bytes>>;
+use hyper::header::HeaderValue;
+use hyper::http::response::Builder;
+use hyper::{Method, Request, Response};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+type Resp = Response<Body>;
 type Req = Request<hyper::body::Incoming>;
 
 pub fn serve(status_code: u16, message: &str) -> Resp {
-    let body = message.to_string().into();
+    let body = body::full(message.to_string());
+    let charset = &config::get().content_charset;
 
     Response::builder()
         .status(status_code)
-        .body(Full::new(body))
+        .header("Content-Type", format!("text/plain; charset={charset}"))
+        .body(body)
         .unwrap()
 }
 
+/// Tells the client how long to wait before trying again.
+pub fn serve_429(message: &str, retry_after: Duration) -> Resp {
+    let mut response = serve(429, message);
+
+    // Round up, so clients don't retry just before they're allowed to.
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+
+    response.headers_mut().insert("Retry-After", HeaderValue::from(seconds));
+    response
+}
+
 pub fn serve_404() -> Resp {
     serve(404, "Resource was not found on this server")
 }
 
-pub async fn try_files(request: &Req) -> Resp {
-    let path = &format!(".{}", request.uri().path());
+pub async fn try_files(request: &Req, encodings: &[Encoding]) -> Resp {
+    let path = &match files::uri_to_local_path(request.uri()) {
+        Some(path) => path,
+        None => return serve_404(),
+    };
+    let request_path = request.uri().path();
+    let fallback = fancy_urls::fallback_for(request_path);
+    let canonical_redirects = config::get().fancy_urls.canonical_redirects;
+
+    if canonical_redirects {
+        if let Some(location) = canonical_location(request_path, path, fallback, request) {
+            // Which variant the shorter URL serves depends on `Accept`.
+            let mut response = serve_redirect(request, &location);
+            negotiation::add_vary_accept(&mut response);
+
+            return response;
+        }
+    }
+
+    if let Some(response) = try_file(path, fallback, request, encodings).await {
+        return response;
+    }
+
+    // Relative links in an index only work with the trailing slash.
+    if canonical_redirects && !request_path.ends_with('/') && is_allowed_directory(path) {
+        return serve_redirect(request, &format!("{request_path}/"));
+    }
 
-    if let Some(response) = try_file(path).await {
+    if let Some(response) = try_index(path, fallback, request, encodings).await {
         response
-    } else if let Some(response) = try_index(path).await {
+    } else if let Some(response) = autoindex::try_listing(path, request).await {
         response
     } else {
         serve_404()
     }
 }
 
-async fn try_file(path: &str) -> Option<Resp> {
-    let path = find_file(path)?;
-    handle_path(&path).await
+/// The outcome of looking for the file behind a request path.
+enum Lookup {
+    /// The path exists as is, or there's just one variant
+    File(String),
+
+    /// Picked among several variants, based on `Accept`
+    Negotiated(String),
+
+    /// Strict negotiation is on, and the client accepts none of the variants
+    NotAcceptable,
+
+    Missing,
+}
+
+impl Lookup {
+    fn path(&self) -> Option<&str> {
+        match self {
+            Lookup::File(path) | Lookup::Negotiated(path) => Some(path),
+            Lookup::NotAcceptable | Lookup::Missing => None,
+        }
+    }
 }
 
-async fn try_index(path: &str) -> Option<Resp> {
-    try_file(&format!("{path}/index")).await
+async fn try_file(path: &str, fallback: &Fallback, request: &Req, encodings: &[Encoding]) -> Option<Resp> {
+    serve_lookup(find_file(path, fallback, request), request, encodings).await
 }
 
-fn find_file(base_path: &str) -> Option<String> {
-    let possible_indexes = vec![
-        format!("{base_path}"),
-        format!("{base_path}.htmd"),
-        format!("{base_path}.txt"),
-        format!("{base_path}.html"),
-        format!("{base_path}.xml"),
-    ];
+async fn try_index(path: &str, fallback: &Fallback, request: &Req, encodings: &[Encoding]) -> Option<Resp> {
+    serve_lookup(find_index(path, fallback, request), request, encodings).await
+}
+
+async fn serve_lookup(lookup: Lookup, request: &Req, encodings: &[Encoding]) -> Option<Resp> {
+    match lookup {
+        Lookup::File(path) => handle_path(&path, request, encodings).await,
+        Lookup::Negotiated(path) => {
+            let mut response = handle_path(&path, request, encodings).await?;
+            negotiation::add_vary_accept(&mut response);
+
+            Some(response)
+        }
+        Lookup::NotAcceptable => {
+            let mut response = serve(
+                406,
+                "Not acceptable; none of the variants of this page match the `Accept` header.",
+            );
+            negotiation::add_vary_accept(&mut response);
+
+            Some(response)
+        }
+        Lookup::Missing => None,
+    }
+}
 
-    possible_indexes
+/// Tries the path as is, and then the variants with each of the
+/// extensions. If there are several, the client's `Accept` header
+/// decides; ties go to the first in the list.
+fn find_file<B>(base_path: &str, fallback: &Fallback, request: &Request<B>) -> Lookup {
+    if file_exists(base_path) {
+        return Lookup::File(base_path.to_string());
+    }
+
+    let variants: Vec<(String, &str)> = fallback
+        .extensions
+        .iter()
+        .map(|extension| (format!("{base_path}.{extension}"), extension.as_str()))
+        .filter(|(file_path, _)| file_exists(file_path))
+        .collect();
+
+    let strict = config::get().fancy_urls.strict_negotiation;
+
+    if variants.is_empty() {
+        return Lookup::Missing;
+    }
+
+    // With just one variant, `Accept` only matters in strict mode.
+    if variants.len() == 1 && !strict {
+        return Lookup::File(variants[0].0.clone());
+    }
+
+    let media_types: Vec<&str> = variants
         .iter()
-        .find(|file_path| file_exists(file_path))
-        .cloned()
+        .map(|&(_, extension)| mime::get_mime_type_by_extension(Some(extension)))
+        .collect();
+
+    match negotiation::choose(request, &media_types) {
+        Some(index) => Lookup::Negotiated(variants[index].0.clone()),
+        None if strict => Lookup::NotAcceptable,
+        None => Lookup::Negotiated(variants[0].0.clone()),
+    }
+}
+
+fn find_index(path: &str, fallback: &Fallback, request: &Req) -> Lookup {
+    let directory = path.trim_end_matches('/');
+
+    fallback
+        .indexes
+        .iter()
+        .map(|index| find_file(&format!("{directory}/{index}"), fallback, request))
+        .find(|lookup| !matches!(lookup, Lookup::Missing))
+        .unwrap_or(Lookup::Missing)
+}
+
+/// The shortest URL serving the same file, if it differs from the one
+/// requested: `/page.html` becomes `/page`, and `/dir/index.html`
+/// becomes `/dir/`.
+fn canonical_location(request_path: &str, path: &str, fallback: &Fallback, request: &Req) -> Option<String> {
+    let (stem, extension) = request_path.rsplit_once('.')?;
+
+    if extension.contains('/') || !fallback.extensions.iter().any(|known| known == extension) {
+        return None;
+    }
+
+    // Don't give away what exists outside of scope.
+    if !files::get_path_buffer_for_allowed_path(path).is_some_and(|path| path.is_file()) {
+        return None;
+    }
+
+    let local_stem = &path[..path.len() - extension.len() - 1];
+    let (directory, name) = stem.rsplit_once('/')?;
+
+    if fallback.indexes.iter().any(|index| index == name) {
+        let (local_directory, _) = local_stem.rsplit_once('/')?;
+
+        if find_index(local_directory, fallback, request).path() == Some(path) {
+            return Some(format!("{directory}/"));
+        }
+    }
+
+    if find_file(local_stem, fallback, request).path() == Some(path) {
+        return Some(stem.to_string());
+    }
+
+    None
+}
+
+/// Permanent redirect, keeping the query string.
+fn serve_redirect(request: &Req, location: &str) -> Resp {
+    let location = match request.uri().query() {
+        Some(query) => format!("{location}?{query}"),
+        None => location.to_string(),
+    };
+
+    Response::builder()
+        .status(301)
+        .header("Location", location)
+        .body(body::empty())
+        .unwrap()
+}
+
+fn is_allowed_directory(path: &str) -> bool {
+    files::get_path_buffer_for_allowed_path(path).is_some_and(|path| path.is_dir())
 }
 
 fn file_exists(path: &str) -> bool {
     Path::new(path).exists()
 }
 
-async fn handle_path(path: &str) -> Option<Resp> {
+async fn handle_path(path: &str, request: &Req, encodings: &[Encoding]) -> Option<Resp> {
     let path_buffer = files::get_path_buffer_for_allowed_path(path)?;
 
     let extension = path_buffer.extension().and_then(|s| s.to_str());
     let mime_type = mime::get_mime_type_by_extension(extension);
 
-    let path = path_buffer.to_str().unwrap();
+    if mime_type == "text/htmd" && render::should_render(request) {
+        return render::serve_rendered(&path_buffer, request).await.ok();
+    }
+
+    if let Some((sidecar, encoding)) = find_precompressed_file(&path_buffer, encodings) {
+        let sidecar = sidecar.to_str().unwrap();
 
-    match serve_file(path, mime_type).await {
-        Ok(response) => Some(response),
-        Err(_error) => None,
+        if let Ok(response) = serve_file(sidecar, mime_type, Some(encoding), request).await {
+            return Some(response);
+        }
     }
+
+    let path = path_buffer.to_str().unwrap();
+
+    serve_file(path, mime_type, None, request).await.ok()
 }
 
-async fn serve_file(filename: &str, mime_type: &str) -> Result<Resp, String> {
-    let body = files::read_file(filename).await?;
-    let charset = config::CONTENT_CHARSET;
+/// Looks for a precompressed version of `path_buffer` (eg. `page.htmd.br`)
+/// in one of the encodings the client accepts.
+fn find_precompressed_file(path_buffer: &Path, encodings: &[Encoding]) -> Option<(PathBuf, Encoding)> {
+    encodings.iter().find_map(|&encoding| {
+        let mut sidecar = path_buffer.as_os_str().to_owned();
+        sidecar.push(format!(".{}", encoding.file_extension()));
 
-    let response = Response::builder()
+        let sidecar = files::get_path_buffer_for_allowed_path(sidecar.to_str()?)?;
+
+        if sidecar.is_file() {
+            Some((sidecar, encoding))
+        } else {
+            None
+        }
+    })
+}
+
+async fn serve_file(
+    filename: &str,
+    mime_type: &str,
+    encoding: Option<Encoding>,
+    request: &Req,
+) -> Result<Resp, String> {
+    let metadata = files::read_file_metadata(filename).await?;
+    let size = metadata.len();
+    let charset = &config::get().content_charset;
+    let content_type = format!("{mime_type}; charset={charset}");
+
+    let mut response = Response::builder()
         .status(200)
-        .header("Content-Type", format!("{mime_type}; charset={charset}"))
-        .body(Full::new(body))
+        .header("Content-Type", &content_type)
+        .header("Accept-Ranges", "bytes");
+
+    if let Some(encoding) = encoding {
+        response = response
+            .header("Content-Encoding", encoding.name())
+            .header("Vary", "Accept-Encoding");
+    }
+
+    let validators = Validators::from_metadata(&metadata);
+
+    if let Some(validators) = &validators {
+        response = response
+            .header("ETag", &validators.etag)
+            .header("Last-Modified", validators.last_modified_header());
+
+        if validators.is_not_modified(request) {
+            return Ok(response.status(304).body(body::empty()).unwrap());
+        }
+    }
+
+    // For `HEAD` requests, we only need the metadata; never read the file.
+    if request.method() == Method::HEAD {
+        response = response.header("Content-Length", size);
+        return Ok(response.body(body::empty()).unwrap());
+    }
+
+    match ranges::requested_ranges(request, validators.as_ref(), size) {
+        RangeRequest::Full => {
+            let file = files::open_file_range(filename, 0, size).await?;
+
+            Ok(response
+                .header("Content-Length", size)
+                .body(body::from_reader(file))
+                .unwrap())
+        }
+        RangeRequest::Satisfiable(ranges) => {
+            serve_ranges(response, filename, size, &ranges, &content_type).await
+        }
+        RangeRequest::Unsatisfiable => Ok(response
+            .status(416)
+            .header("Content-Range", ranges::unsatisfiable_content_range_header(size))
+            .body(body::empty())
+            .unwrap()),
+    }
+}
+
+async fn serve_ranges(
+    response: Builder,
+    filename: &str,
+    size: u64,
+    ranges: &[ByteRange],
+    content_type: &str,
+) -> Result<Resp, String> {
+    let response = response.status(206);
+
+    if let [range] = ranges {
+        let file = files::open_file_range(filename, range.start, range.len()).await?;
+
+        return Ok(response
+            .header("Content-Range", range.content_range_header(size))
+            .header("Content-Length", range.len())
+            .body(body::from_reader(file))
+            .unwrap());
+    }
+
+    let boundary = ranges::generate_boundary();
+    let mut content_length = 0;
+    let mut reader: Box<dyn AsyncRead + Send + Unpin> = Box::new(tokio::io::empty());
+
+    for range in ranges {
+        let part_header = ranges::multipart_part_header(range, size, content_type, &boundary);
+        let file = files::open_file_range(filename, range.start, range.len()).await?;
+
+        content_length += part_header.len() as u64 + range.len();
+        reader = Box::new(reader.chain(Cursor::new(part_header)).chain(file));
+    }
+
+    let trailer = ranges::multipart_trailer(&boundary);
+    content_length += trailer.len() as u64;
+    reader = Box::new(reader.chain(Cursor::new(trailer)));
+
+    let mut response = response
+        .header("Content-Length", content_length)
+        .body(body::from_reader(reader))
         .unwrap();
 
+    let multipart_content_type = format!("multipart/byteranges; boundary={boundary}");
+
+    response
+        .headers_mut()
+        .insert("Content-Type", HeaderValue::from_str(&multipart_content_type).unwrap());
+
     Ok(response)
 }
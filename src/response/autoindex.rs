@@ -0,0 +1,299 @@
+use crate::body::{self, Body};
+use crate::config::{self, AutoindexConfig};
+use crate::files;
+use crate::htmd;
+use crate::response::negotiation;
+
+use std::cmp::Ordering;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use hyper::{Request, Response};
+
+type Resp = Response<Body>;
+type Req = Request<hyper::body::Incoming>;
+
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Html,
+    Json,
+    Htmd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+/// Lists the directory at `path`, unless autoindex is disabled, or the
+/// directory opted out using the marker file.
+pub async fn try_listing(path: &str, request: &Req) -> Option<Resp> {
+    let config = &config::get().autoindex;
+
+    if !config.enabled {
+        return None;
+    }
+
+    let directory = files::get_path_buffer_for_allowed_path(path)?;
+
+    if !directory.is_dir() || directory.join(&config.opt_out_file).exists() {
+        return None;
+    }
+
+    let mut entries = read_entries(&directory, config).await?;
+
+    let (sort_key, descending) = sort_order(request);
+    sort_entries(&mut entries, sort_key, descending);
+
+    // Links are absolute, so they work with or without a trailing slash.
+    let base = match request.uri().path() {
+        path if path.ends_with('/') => path.to_string(),
+        path => format!("{path}/"),
+    };
+
+    let format = negotiate_format(request);
+    let charset = &config::get().content_charset;
+
+    let (content_type, listing) = match format {
+        Format::Html => ("text/html", render_html(&base, &entries)),
+        Format::Json => ("application/json", render_json(&base, &entries)),
+        Format::Htmd => ("text/htmd", render_htmd(&base, &entries)),
+    };
+
+    let mut response = Response::builder()
+        .status(200)
+        .header("Content-Type", format!("{content_type}; charset={charset}"))
+        .header("Content-Length", listing.len())
+        .body(body::full(listing))
+        .unwrap();
+
+    negotiation::add_vary_accept(&mut response);
+    Some(response)
+}
+
+async fn read_entries(directory: &Path, config: &AutoindexConfig) -> Option<Vec<Entry>> {
+    let mut read_dir = tokio::fs::read_dir(directory).await.ok()?;
+    let mut entries = Vec::new();
+
+    while let Ok(Some(entry)) = read_dir.next_entry().await {
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        if name.starts_with('.') && !config.show_hidden {
+            continue;
+        }
+
+        // Follows symlinks, so those show up as what they point to.
+        let metadata = match tokio::fs::metadata(entry.path()).await {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+
+        entries.push(Entry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok().map(DateTime::<Utc>::from),
+        });
+    }
+
+    Some(entries)
+}
+
+/// `?sort=name|size|modified` and `?order=asc|desc`; by name by default.
+fn sort_order(request: &Req) -> (SortKey, bool) {
+    let mut sort_key = SortKey::Name;
+    let mut descending = false;
+
+    for (key, value) in query_pairs(request) {
+        match (key, value) {
+            ("sort", "name") => sort_key = SortKey::Name,
+            ("sort", "size") => sort_key = SortKey::Size,
+            ("sort", "modified") => sort_key = SortKey::Modified,
+            ("order", "asc") => descending = false,
+            ("order", "desc") => descending = true,
+            _ => (),
+        }
+    }
+
+    (sort_key, descending)
+}
+
+fn query_pairs(request: &Req) -> impl Iterator<Item = (&str, &str)> {
+    request
+        .uri()
+        .query()
+        .unwrap_or("")
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+}
+
+/// Directories always come first; the order only applies within them.
+fn sort_entries(entries: &mut [Entry], sort_key: SortKey, descending: bool) {
+    entries.sort_by(|a, b| {
+        let ordering = match sort_key {
+            SortKey::Name => a.name.cmp(&b.name),
+            SortKey::Size => a.size.cmp(&b.size).then_with(|| a.name.cmp(&b.name)),
+            SortKey::Modified => a.modified.cmp(&b.modified).then_with(|| a.name.cmp(&b.name)),
+        };
+
+        let ordering = if descending { ordering.reverse() } else { ordering };
+
+        match (a.is_dir, b.is_dir) {
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            _ => ordering,
+        }
+    });
+}
+
+/// Browsers get HTML, scripts asking for JSON get JSON, and gridsite
+/// browsers get htmd.
+fn negotiate_format(request: &Req) -> Format {
+    let formats = [Format::Html, Format::Json, Format::Htmd];
+    let media_types = ["text/html", "application/json", "text/htmd"];
+
+    match negotiation::choose(request, &media_types) {
+        Some(index) => formats[index],
+        None => Format::Html,
+    }
+}
+
+fn render_html(base: &str, entries: &[Entry]) -> String {
+    let title = htmd::escape(base);
+    let mut rows = String::new();
+
+    if let Some(parent) = parent_href(base) {
+        rows.push_str(&format!(
+            "<tr><td><a href=\"{}\">../</a></td><td>-</td><td>-</td></tr>\n",
+            htmd::escape(parent)
+        ));
+    }
+
+    for entry in entries {
+        rows.push_str(&format!(
+            "<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            htmd::escape(&entry.href(base)),
+            htmd::escape(&entry.display_name()),
+            entry.display_size(),
+            entry.display_modified(),
+        ));
+    }
+
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Index of {title}</title>\n</head>\n<body>\n<h1>Index of {title}</h1>\n<table>\n<tr><th><a href=\"?sort=name\">Name</a></th><th><a href=\"?sort=size\">Size</a></th><th><a href=\"?sort=modified\">Modified</a></th></tr>\n{rows}</table>\n</body>\n</html>\n"
+    )
+}
+
+fn render_json(base: &str, entries: &[Entry]) -> String {
+    let entries: Vec<serde_json::Value> = entries
+        .iter()
+        .map(|entry| {
+            serde_json::json!({
+                "name": entry.name,
+                "type": if entry.is_dir { "directory" } else { "file" },
+                "size": entry.size,
+                "modified": entry.modified.map(|modified| modified.to_rfc3339()),
+                "href": entry.href(base),
+            })
+        })
+        .collect();
+
+    let listing = serde_json::json!({ "path": base, "entries": entries });
+    format!("{listing}\n")
+}
+
+fn render_htmd(base: &str, entries: &[Entry]) -> String {
+    let mut listing = format!("# Index of {base}\n\n");
+
+    if let Some(parent) = parent_href(base) {
+        listing.push_str(&format!("- [../]({parent})\n"));
+    }
+
+    for entry in entries {
+        listing.push_str(&format!(
+            "- [{}]({}) {} {}\n",
+            entry.display_name(),
+            entry.href(base),
+            entry.display_size(),
+            entry.display_modified(),
+        ));
+    }
+
+    listing
+}
+
+/// `/a/b/` becomes `/a/`; the root doesn't have a parent.
+fn parent_href(base: &str) -> Option<&str> {
+    let trimmed = base.strip_suffix('/')?;
+    let index = trimmed.rfind('/')?;
+
+    Some(&base[..=index])
+}
+
+impl Entry {
+    fn href(&self, base: &str) -> String {
+        let name = percent_encode(&self.name);
+
+        if self.is_dir {
+            format!("{base}{name}/")
+        } else {
+            format!("{base}{name}")
+        }
+    }
+
+    fn display_name(&self) -> String {
+        if self.is_dir {
+            format!("{}/", self.name)
+        } else {
+            self.name.clone()
+        }
+    }
+
+    fn display_size(&self) -> String {
+        if self.is_dir {
+            return String::from("-");
+        }
+
+        let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+        let mut size = self.size as f64;
+        let mut unit = 0;
+
+        while size >= 1024.0 && unit < units.len() - 1 {
+            size /= 1024.0;
+            unit += 1;
+        }
+
+        match unit {
+            0 => format!("{} B", self.size),
+            _ => format!("{size:.1} {}", units[unit]),
+        }
+    }
+
+    fn display_modified(&self) -> String {
+        match self.modified {
+            Some(modified) => modified.format("%Y-%m-%d %H:%M").to_string(),
+            None => String::from("-"),
+        }
+    }
+}
+
+/// Encodes everything but unreserved characters, so names can be used
+/// as a single path segment.
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            byte => format!("%{byte:02X}"),
+        })
+        .collect()
+}
@@ -0,0 +1,129 @@
+use crate::body::{self, Body};
+use crate::config;
+use crate::fancy_urls;
+use crate::files;
+use crate::response::{find_file, mime, negotiation, render, Lookup};
+
+use std::path::Path;
+
+use hyper::header::HeaderValue;
+use hyper::{Request, Response, StatusCode};
+
+type Resp = Response<Body>;
+type Req = Request<hyper::body::Incoming>;
+
+/// The parts of a request needed to pick an error page, kept around
+/// after the request itself has been handled.
+pub fn snapshot(request: &Req) -> Request<()> {
+    let mut head = Request::builder()
+        .method(request.method().clone())
+        .uri(request.uri().clone());
+
+    if let Some(accept) = request.headers().get("Accept") {
+        head = head.header("Accept", accept.clone());
+    }
+
+    head.body(()).unwrap()
+}
+
+/// Replaces the body of an error response with the site's own page for
+/// that status (eg. `404.htmd`), if there is one. The status code and
+/// the other headers are kept as they are.
+pub async fn apply(response: Resp, request: &Request<()>) -> Resp {
+    let status = response.status();
+
+    if !status.is_client_error() && !status.is_server_error() {
+        return response;
+    }
+
+    let (path, negotiated) = match find_error_page(status.as_u16(), request) {
+        Some(Lookup::File(path)) => (path, false),
+        Some(Lookup::Negotiated(path)) => (path, true),
+        _ => return response,
+    };
+
+    let (content_type, page) = match read_error_page(&path, request).await {
+        Ok(page) => page,
+        Err(error) => {
+            eprintln!("Couldn't serve error page {path}: {error}");
+            return response;
+        }
+    };
+
+    let (mut parts, _) = response.into_parts();
+
+    // The validators belonged to whatever couldn't be served.
+    for header in ["ETag", "Last-Modified", "Content-Encoding"] {
+        parts.headers.remove(header);
+    }
+
+    // Except for a 416, where it tells the client the actual size.
+    if status != StatusCode::RANGE_NOT_SATISFIABLE {
+        parts.headers.remove("Content-Range");
+    }
+
+    parts.headers.insert("Content-Type", HeaderValue::from_str(&content_type).unwrap());
+    parts.headers.insert("Content-Length", HeaderValue::from(page.len()));
+
+    let mut response = Response::from_parts(parts, body::full(page));
+
+    // Whether htmd is rendered depends on `Accept` too.
+    if negotiated || path.ends_with(".htmd") {
+        negotiation::add_vary_accept(&mut response);
+    }
+
+    response
+}
+
+/// Looks for `<status>` with any of the page extensions, in the directory
+/// of the request and then in each of its parents up to the scope.
+fn find_error_page(status: u16, request: &Request<()>) -> Option<Lookup> {
+    let scope = Path::new(&config::get().scope).canonicalize().ok()?;
+    let request_path = request.uri().path();
+    let directory = files::normalize_path(&scope, request_path)?;
+    let fallback = fancy_urls::fallback_for(request_path);
+
+    for directory in directory.ancestors().take_while(|directory| directory.starts_with(&scope)) {
+        if !directory.is_dir() {
+            continue;
+        }
+
+        let base_path = directory.join(status.to_string());
+
+        match find_file(base_path.to_str()?, fallback, request) {
+            Lookup::Missing => continue,
+            lookup => return Some(lookup),
+        }
+    }
+
+    None
+}
+
+/// Returns the Content-Type and body of the page. Like normal pages,
+/// htmd is rendered or served as plain text for browsers that don't
+/// support it.
+async fn read_error_page(path: &str, request: &Request<()>) -> Result<(String, Vec<u8>), String> {
+    let path_buffer = files::get_path_buffer_for_allowed_path(path)
+        .ok_or_else(|| String::from("it's outside of the scope."))?;
+
+    let extension = path_buffer.extension().and_then(|s| s.to_str());
+    let mime_type = mime::get_mime_type_by_extension(extension);
+    let charset = &config::get().content_charset;
+
+    if mime_type == "text/htmd" && render::should_render(request) {
+        let html = render::render_to_html(&path_buffer).await?;
+        return Ok((format!("text/html; charset={charset}"), html.to_vec()));
+    }
+
+    let page = tokio::fs::read(&path_buffer)
+        .await
+        .map_err(|error| error.to_string())?;
+
+    let content_type = if mime_type == "text/htmd" && !negotiation::accepts_explicitly(request, "text/htmd") {
+        String::from("text/plain")
+    } else {
+        format!("{mime_type}; charset={charset}")
+    };
+
+    Ok((content_type, page))
+}
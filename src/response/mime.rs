@@ -144,6 +144,7 @@ pub fn get_mime_type_by_extension(extension: Option<&str>) -> &'static str {
         Some("fsx") => "application/fsharp-script",
         Some("generictest") => "application/xml",
         Some("gif") => "image/gif",
+        Some("gmi") => "text/gemini",
         Some("group") => "text/x-ms-group; charset=utf8",
         Some("gsm") => "audio/x-gsm",
         Some("gtar") => "application/x-gtar",
@@ -239,7 +240,9 @@ pub fn get_mime_type_by_extension(extension: Option<&str>) -> &'static str {
         Some("man") => "application/x-troff-man",
         Some("manifest") => "application/x-ms-manifest",
         Some("map") => "text/plain; charset=utf8",
+        Some("markdown") => "text/markdown",
         Some("master") => "application/xml",
+        Some("md") => "text/markdown",
         Some("mda") => "application/msaccess",
         Some("mdb") => "application/x-msaccess",
         Some("mde") => "application/msaccess",
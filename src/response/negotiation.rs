@@ -0,0 +1,186 @@
+use crate::body::Body;
+
+use hyper::header::HeaderValue;
+use hyper::{Request, Response};
+
+type Resp = Response<Body>;
+
+/// A single entry from the `Accept` header, eg. `text/*;q=0.8`.
+struct MediaRange {
+    type_: String,
+    subtype: String,
+    quality: f32,
+}
+
+/// Picks the media type the client prefers, using the q-values from the
+/// `Accept` header. Types the client names explicitly win over ones it
+/// only accepts through a wildcard, and the order of `media_types`
+/// breaks any remaining ties. Returns `None` if none are acceptable.
+pub fn choose<B>(request: &Request<B>, media_types: &[&str]) -> Option<usize> {
+    let ranges = match request.headers().get("Accept").and_then(|header| header.to_str().ok()) {
+        Some(header) => parse_accept(header),
+        None => return (!media_types.is_empty()).then_some(0),
+    };
+
+    let mut best: Option<(usize, f32, u8)> = None;
+
+    for (index, media_type) in media_types.iter().enumerate() {
+        let (quality, specificity) = match match_quality(&ranges, media_type) {
+            Some(matched) if matched.0 > 0.0 => matched,
+            _ => continue,
+        };
+
+        let better = match best {
+            Some((_, best_quality, best_specificity)) => {
+                quality > best_quality || (quality == best_quality && specificity > best_specificity)
+            }
+            None => true,
+        };
+
+        if better {
+            best = Some((index, quality, specificity));
+        }
+    }
+
+    best.map(|(index, _, _)| index)
+}
+
+/// Whether the client names the media type itself (with a non-zero
+/// quality), rather than only accepting it through a wildcard.
+pub fn accepts_explicitly<B>(request: &Request<B>, media_type: &str) -> bool {
+    let ranges = match request.headers().get("Accept").and_then(|header| header.to_str().ok()) {
+        Some(header) => parse_accept(header),
+        None => return false,
+    };
+
+    matches!(match_quality(&ranges, media_type), Some((quality, 2)) if quality > 0.0)
+}
+
+/// The response depends on the `Accept` header, so caches must take
+/// it into account.
+pub fn add_vary_accept(response: &mut Resp) {
+    let varies = response
+        .headers()
+        .get_all("Vary")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case("Accept"));
+
+    if !varies {
+        response.headers_mut().append("Vary", HeaderValue::from_static("Accept"));
+    }
+}
+
+fn parse_accept(header: &str) -> Vec<MediaRange> {
+    header
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let (type_, subtype) = params.next()?.trim().split_once('/')?;
+
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|quality| quality.trim().parse::<f32>().ok())
+                .unwrap_or(1.0)
+                .clamp(0.0, 1.0);
+
+            Some(MediaRange {
+                type_: type_.trim().to_lowercase(),
+                subtype: subtype.trim().to_lowercase(),
+                quality,
+            })
+        })
+        .collect()
+}
+
+/// Returns the quality of the most specific range matching the media
+/// type, together with how specific it was (`*/*` is 0, `text/*` is 1
+/// and `text/html` is 2).
+fn match_quality(ranges: &[MediaRange], media_type: &str) -> Option<(f32, u8)> {
+    let essence = media_type.split(';').next().unwrap_or("").trim().to_lowercase();
+    let (type_, subtype) = essence.split_once('/')?;
+
+    ranges
+        .iter()
+        .filter_map(|range| {
+            let specificity = match (range.type_.as_str(), range.subtype.as_str()) {
+                ("*", "*") => 0,
+                (t, "*") if t == type_ => 1,
+                (t, s) if t == type_ && s == subtype => 2,
+                _ => return None,
+            };
+
+            Some((range.quality, specificity))
+        })
+        .max_by_key(|&(_, specificity)| specificity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(accept: Option<&str>) -> Request<()> {
+        let mut builder = Request::builder();
+
+        if let Some(accept) = accept {
+            builder = builder.header("Accept", accept);
+        }
+
+        builder.body(()).unwrap()
+    }
+
+    fn choose_for(accept: Option<&str>, media_types: &[&str]) -> Option<usize> {
+        choose(&request(accept), media_types)
+    }
+
+    #[test]
+    fn the_most_specific_range_decides() {
+        let ranges = parse_accept("*/*;q=0.1, text/*;q=0.5, text/html");
+
+        assert_eq!(match_quality(&ranges, "text/html"), Some((1.0, 2)));
+        assert_eq!(match_quality(&ranges, "text/plain; charset=utf-8"), Some((0.5, 1)));
+        assert_eq!(match_quality(&ranges, "image/png"), Some((0.1, 0)));
+        assert_eq!(match_quality(&parse_accept("text/html"), "image/png"), None);
+    }
+
+    #[test]
+    fn explicit_types_win_ties_over_wildcards() {
+        let types = ["text/htmd", "text/html"];
+
+        assert_eq!(choose_for(Some("text/html, */*"), &types), Some(1));
+        assert_eq!(choose_for(Some("text/htmd, */*"), &types), Some(0));
+        assert_eq!(choose_for(Some("TEXT/HTML, */*"), &types), Some(1));
+    }
+
+    #[test]
+    fn higher_quality_wins() {
+        let types = ["text/htmd", "text/html"];
+
+        assert_eq!(choose_for(Some("text/html;q=0.5, text/htmd;q=0.8"), &types), Some(0));
+        assert_eq!(choose_for(Some("text/html;q=0.9, */*"), &types), Some(0));
+    }
+
+    #[test]
+    fn zero_quality_excludes() {
+        let types = ["text/htmd", "text/html"];
+
+        assert_eq!(choose_for(Some("*/*, text/htmd;q=0"), &types), Some(1));
+        assert_eq!(choose_for(Some("text/*;q=0"), &types), None);
+        assert_eq!(choose_for(Some("image/png"), &types), None);
+        assert!(!accepts_explicitly(&request(Some("text/htmd;q=0")), "text/htmd"));
+    }
+
+    #[test]
+    fn ties_go_to_the_first_type() {
+        assert_eq!(choose_for(Some("*/*"), &["text/htmd", "text/html"]), Some(0));
+        assert_eq!(choose_for(Some("text/html, text/plain"), &["text/plain", "text/html"]), Some(0));
+    }
+
+    #[test]
+    fn anything_goes_without_an_accept_header() {
+        assert_eq!(choose_for(None, &["text/htmd", "text/html"]), Some(0));
+        assert_eq!(choose_for(None, &[]), None);
+        assert!(!accepts_explicitly(&request(None), "text/html"));
+    }
+}
@@ -0,0 +1,299 @@
+use crate::response::validators::Validators;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use httpdate::HttpDate;
+use hyper::body::Incoming;
+use hyper::Request;
+
+type Req = Request<Incoming>;
+
+/// More ranges than this in a single request are ignored, and the whole
+/// file is served instead.
+const MAX_RANGES: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    /// Inclusive, like in the `Range` header itself.
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    pub fn content_range_header(&self, size: u64) -> String {
+        format!("bytes {}-{}/{size}", self.start, self.end)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeRequest {
+    /// No (usable) `Range` header; serve the whole file.
+    Full,
+    Satisfiable(Vec<ByteRange>),
+    Unsatisfiable,
+}
+
+/// Figures out which parts of a file of `size` bytes the client asked for,
+/// taking `If-Range` into account.
+pub fn requested_ranges(request: &Req, validators: Option<&Validators>, size: u64) -> RangeRequest {
+    let header = match request.headers().get("Range").and_then(|h| h.to_str().ok()) {
+        Some(header) => header,
+        None => return RangeRequest::Full,
+    };
+
+    if !if_range_matches(request, validators) {
+        return RangeRequest::Full;
+    }
+
+    parse_range_header(header, size)
+}
+
+pub fn unsatisfiable_content_range_header(size: u64) -> String {
+    format!("bytes */{size}")
+}
+
+fn if_range_matches<B>(request: &Request<B>, validators: Option<&Validators>) -> bool {
+    let if_range = match request.headers().get("If-Range").and_then(|h| h.to_str().ok()) {
+        Some(if_range) => if_range.trim(),
+        None => return true,
+    };
+
+    let validators = match validators {
+        Some(validators) => validators,
+        None => return false,
+    };
+
+    // If-Range requires a strong comparison; weak ETags never match.
+    if if_range.starts_with('"') {
+        return if_range == validators.etag;
+    }
+
+    match if_range.parse::<HttpDate>() {
+        Ok(date) => date == HttpDate::from(validators.last_modified),
+        Err(_) => false,
+    }
+}
+
+fn parse_range_header(header: &str, size: u64) -> RangeRequest {
+    let specs = match header.trim().strip_prefix("bytes=") {
+        Some(specs) => specs,
+        None => return RangeRequest::Full,
+    };
+
+    let mut ranges = Vec::new();
+
+    for spec in specs.split(',').map(str::trim).filter(|spec| !spec.is_empty()) {
+        match parse_range_spec(spec, size) {
+            Ok(Some(range)) => ranges.push(range),
+            Ok(None) => (),
+            // Syntactically invalid headers must be ignored.
+            Err(()) => return RangeRequest::Full,
+        }
+    }
+
+    if ranges.len() > MAX_RANGES {
+        RangeRequest::Full
+    } else if ranges.is_empty() {
+        RangeRequest::Unsatisfiable
+    } else {
+        RangeRequest::Satisfiable(coalesce(ranges))
+    }
+}
+
+/// Merges ranges that overlap or are adjacent, so no part of the file is
+/// sent more than once (RFC 9110, section 14.3). The result is sorted.
+fn coalesce(mut ranges: Vec<ByteRange>) -> Vec<ByteRange> {
+    ranges.sort_by_key(|range| range.start);
+
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+
+    merged
+}
+
+/// Parses a single `first-last`, `first-` or `-suffix` spec. Returns
+/// `Ok(None)` for ranges that are valid but lie outside of the file.
+fn parse_range_spec(spec: &str, size: u64) -> Result<Option<ByteRange>, ()> {
+    let (first, last) = spec.split_once('-').ok_or(())?;
+    let (first, last) = (first.trim(), last.trim());
+
+    if first.is_empty() {
+        let suffix: u64 = last.parse().map_err(|_| ())?;
+
+        if suffix == 0 || size == 0 {
+            return Ok(None);
+        }
+
+        let start = size.saturating_sub(suffix);
+        return Ok(Some(ByteRange { start, end: size - 1 }));
+    }
+
+    let start: u64 = first.parse().map_err(|_| ())?;
+    let end = match last {
+        "" => u64::MAX,
+        last => last.parse().map_err(|_| ())?,
+    };
+
+    if end < start {
+        return Err(());
+    }
+
+    if start >= size {
+        return Ok(None);
+    }
+
+    Ok(Some(ByteRange {
+        start,
+        end: end.min(size - 1),
+    }))
+}
+
+pub fn generate_boundary() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or_default();
+
+    format!("waiter-{nanos:x}")
+}
+
+/// Headers preceding each part of a `multipart/byteranges` body.
+pub fn multipart_part_header(range: &ByteRange, size: u64, content_type: &str, boundary: &str) -> String {
+    format!(
+        "\r\n--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: {}\r\n\r\n",
+        range.content_range_header(size)
+    )
+}
+
+pub fn multipart_trailer(boundary: &str) -> String {
+    format!("\r\n--{boundary}--\r\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    fn range(start: u64, end: u64) -> ByteRange {
+        ByteRange { start, end }
+    }
+
+    fn validators() -> Validators {
+        Validators {
+            etag: String::from("\"abc\""),
+            last_modified: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        }
+    }
+
+    fn request_with_if_range(if_range: &str) -> Request<()> {
+        Request::builder().header("If-Range", if_range).body(()).unwrap()
+    }
+
+    #[test]
+    fn parses_range_specs() {
+        assert_eq!(parse_range_spec("0-9", 100), Ok(Some(range(0, 9))));
+        assert_eq!(parse_range_spec("90-", 100), Ok(Some(range(90, 99))));
+        assert_eq!(parse_range_spec("90-200", 100), Ok(Some(range(90, 99))));
+        assert_eq!(parse_range_spec("-10", 100), Ok(Some(range(90, 99))));
+        assert_eq!(parse_range_spec("-200", 100), Ok(Some(range(0, 99))));
+        assert_eq!(parse_range_spec(" 5 - 6 ", 100), Ok(Some(range(5, 6))));
+    }
+
+    #[test]
+    fn range_specs_outside_of_the_file_are_unsatisfiable() {
+        assert_eq!(parse_range_spec("100-", 100), Ok(None));
+        assert_eq!(parse_range_spec("-0", 100), Ok(None));
+        assert_eq!(parse_range_spec("-5", 0), Ok(None));
+    }
+
+    #[test]
+    fn invalid_range_specs_are_rejected() {
+        assert_eq!(parse_range_spec("9-0", 100), Err(()));
+        assert_eq!(parse_range_spec("a-b", 100), Err(()));
+        assert_eq!(parse_range_spec("-", 100), Err(()));
+        assert_eq!(parse_range_spec("5", 100), Err(()));
+    }
+
+    #[test]
+    fn parses_range_headers() {
+        assert_eq!(parse_range_header("bytes=0-9", 100), RangeRequest::Satisfiable(vec![range(0, 9)]));
+        assert_eq!(parse_range_header("items=0-9", 100), RangeRequest::Full);
+        assert_eq!(parse_range_header("bytes=0-9,x", 100), RangeRequest::Full);
+        assert_eq!(parse_range_header("bytes=100-", 100), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn overlapping_and_adjacent_ranges_are_merged() {
+        let header = format!("bytes={}", vec!["0-"; MAX_RANGES].join(","));
+        assert_eq!(parse_range_header(&header, 100), RangeRequest::Satisfiable(vec![range(0, 99)]));
+
+        assert_eq!(
+            parse_range_header("bytes=50-59,0-9,10-19,55-70,90-", 100),
+            RangeRequest::Satisfiable(vec![range(0, 19), range(50, 70), range(90, 99)])
+        );
+    }
+
+    #[test]
+    fn too_many_ranges_serve_the_whole_file() {
+        let header = format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","));
+        assert_eq!(parse_range_header(&header, 100), RangeRequest::Full);
+    }
+
+    #[test]
+    fn if_range_compares_etags_strongly() {
+        let validators = validators();
+
+        assert!(if_range_matches(&request_with_if_range("\"abc\""), Some(&validators)));
+        assert!(!if_range_matches(&request_with_if_range("\"def\""), Some(&validators)));
+        assert!(!if_range_matches(&request_with_if_range("W/\"abc\""), Some(&validators)));
+    }
+
+    #[test]
+    fn if_range_compares_dates_exactly() {
+        let validators = validators();
+        let date = validators.last_modified_header();
+        let later = httpdate::fmt_http_date(validators.last_modified + Duration::from_secs(1));
+
+        assert!(if_range_matches(&request_with_if_range(&date), Some(&validators)));
+        assert!(!if_range_matches(&request_with_if_range(&later), Some(&validators)));
+        assert!(!if_range_matches(&request_with_if_range("yesterday"), Some(&validators)));
+    }
+
+    #[test]
+    fn if_range_without_validators_never_matches() {
+        assert!(!if_range_matches(&request_with_if_range("\"abc\""), None));
+        assert!(if_range_matches(&Request::new(()), None));
+    }
+
+    #[test]
+    fn frames_multipart_bodies() {
+        let content = b"0123456789";
+        let ranges = [range(0, 1), range(8, 9)];
+        let mut body = Vec::new();
+
+        for range in &ranges {
+            body.extend(multipart_part_header(range, 10, "text/plain", "sep").into_bytes());
+            body.extend(&content[range.start as usize..=range.end as usize]);
+        }
+
+        body.extend(multipart_trailer("sep").into_bytes());
+
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "\r\n--sep\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+             \r\n--sep\r\nContent-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\n89\
+             \r\n--sep--\r\n"
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use crate::body::{self, Body};
+use crate::config;
+use crate::files;
+use crate::htmd;
+use crate::response::negotiation;
+use crate::response::validators::Validators;
+
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+use hyper::body::Bytes;
+use hyper::{Method, Request, Response};
+
+type Resp = Response<Body>;
+type Req = Request<hyper::body::Incoming>;
+
+static CACHE: OnceLock<Mutex<HashMap<PathBuf, RenderedPage>>> = OnceLock::new();
+
+/// Used when the site doesn't provide a template of its own.
+const DEFAULT_TEMPLATE: &str = "<!DOCTYPE html>
+<html lang=\"{{lang}}\">
+<head>
+<meta charset=\"utf-8\">
+<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">
+<title>{{title}}</title>
+</head>
+<body>
+{{content}}</body>
+</html>
+";
+
+#[derive(Clone)]
+struct RenderedPage {
+    /// Modification times of the page and its template when it was rendered
+    modified: SystemTime,
+    template: Option<(PathBuf, SystemTime)>,
+    html: Bytes,
+    etag: String,
+}
+
+/// Whether to render htmd pages to HTML for this client.
+pub fn should_render<B>(request: &Request<B>) -> bool {
+    config::get().render.enabled && !negotiation::accepts_explicitly(request, "text/htmd")
+}
+
+/// Serves the htmd page at `path` as HTML, wrapped in the nearest
+/// template. Rendered pages are cached until the page or its template
+/// changes.
+pub async fn serve_rendered(path: &Path, request: &Req) -> Result<Resp, String> {
+    let page = rendered_page(path).await?;
+
+    let last_modified = match &page.template {
+        Some((_, template_modified)) => page.modified.max(*template_modified),
+        None => page.modified,
+    };
+
+    let validators = Validators {
+        etag: page.etag.clone(),
+        last_modified,
+    };
+
+    let charset = &config::get().content_charset;
+
+    let response = Response::builder()
+        .header("Content-Type", format!("text/html; charset={charset}"))
+        .header("ETag", &validators.etag)
+        .header("Last-Modified", validators.last_modified_header());
+
+    let mut response = if validators.is_not_modified(request) {
+        response.status(304).body(body::empty())
+    } else if request.method() == Method::HEAD {
+        response.status(200).header("Content-Length", page.html.len()).body(body::empty())
+    } else {
+        response.status(200).header("Content-Length", page.html.len()).body(body::full(page.html))
+    }
+    .unwrap();
+
+    // Whether the page is rendered depends on `Accept`.
+    negotiation::add_vary_accept(&mut response);
+
+    Ok(response)
+}
+
+/// Renders the page to HTML, without any of the headers.
+pub async fn render_to_html(path: &Path) -> Result<Bytes, String> {
+    Ok(rendered_page(path).await?.html)
+}
+
+async fn rendered_page(path: &Path) -> Result<RenderedPage, String> {
+    let modified = modified_time(path).await?;
+
+    let template = match find_template(path) {
+        Some(template) => {
+            let template_modified = modified_time(&template).await?;
+            Some((template, template_modified))
+        }
+        None => None,
+    };
+
+    match cached_page(path, modified, &template) {
+        Some(page) => Ok(page),
+        None => render_page(path, modified, template).await,
+    }
+}
+
+fn cached_page(
+    path: &Path,
+    modified: SystemTime,
+    template: &Option<(PathBuf, SystemTime)>,
+) -> Option<RenderedPage> {
+    let cache = cache().lock().unwrap();
+    let page = cache.get(path)?;
+
+    (page.modified == modified && &page.template == template).then(|| page.clone())
+}
+
+async fn render_page(
+    path: &Path,
+    modified: SystemTime,
+    template: Option<(PathBuf, SystemTime)>,
+) -> Result<RenderedPage, String> {
+    let source = read_to_string(path).await?;
+
+    let template_source = match &template {
+        Some((template, _)) => read_to_string(template).await?,
+        None => DEFAULT_TEMPLATE.to_string(),
+    };
+
+    let rendered = htmd::to_html(&source);
+
+    let title = rendered.title.unwrap_or_else(|| {
+        let stem = path.file_stem().unwrap_or_default();
+        stem.to_string_lossy().into_owned()
+    });
+
+    let html = template_source
+        .replace("{{lang}}", &htmd::escape(&config::get().content_language))
+        .replace("{{title}}", &htmd::escape(&title))
+        .replace("{{content}}", &rendered.html);
+
+    let mut hasher = DefaultHasher::new();
+    html.hash(&mut hasher);
+
+    let page = RenderedPage {
+        modified,
+        template,
+        etag: format!("\"{:x}\"", hasher.finish()),
+        html: Bytes::from(html),
+    };
+
+    store_page(path, page.clone());
+    Ok(page)
+}
+
+/// Keeps the cache bounded by dropping an arbitrary page when it's full.
+fn store_page(path: &Path, page: RenderedPage) {
+    let cache_size = config::get().render.cache_size;
+
+    if cache_size == 0 {
+        return;
+    }
+
+    let mut cache = cache().lock().unwrap();
+
+    if cache.len() >= cache_size && !cache.contains_key(path) {
+        if let Some(evicted) = cache.keys().next().cloned() {
+            cache.remove(&evicted);
+        }
+    }
+
+    cache.insert(path.to_path_buf(), page);
+}
+
+/// Looks for the template next to the page, and then in each parent
+/// directory up to the scope.
+fn find_template(path: &Path) -> Option<PathBuf> {
+    let template_name = &config::get().render.template_name;
+    let scope = Path::new(&config::get().scope).canonicalize().ok()?;
+
+    path.ancestors()
+        .skip(1)
+        .take_while(|directory| directory.starts_with(&scope))
+        .map(|directory| directory.join(template_name))
+        .find(|template| template.is_file())
+        .and_then(|template| files::get_path_buffer_for_allowed_path(template.to_str()?))
+}
+
+fn cache() -> &'static Mutex<HashMap<PathBuf, RenderedPage>> {
+    CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+async fn modified_time(path: &Path) -> Result<SystemTime, String> {
+    tokio::fs::metadata(path)
+        .await
+        .and_then(|metadata| metadata.modified())
+        .map_err(|error| format!("Couldn't read {}: {error}", path.display()))
+}
+
+async fn read_to_string(path: &Path) -> Result<String, String> {
+    tokio::fs::read_to_string(path)
+        .await
+        .map_err(|error| format!("Couldn't read {}: {error}", path.display()))
+}
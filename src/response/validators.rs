@@ -0,0 +1,75 @@
+use std::fs::Metadata;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use httpdate::HttpDate;
+use hyper::body::Incoming;
+use hyper::Request;
+
+type Req = Request<Incoming>;
+
+#[derive(Debug, Clone)]
+pub struct Validators {
+    pub etag: String,
+    pub last_modified: SystemTime,
+}
+
+impl Validators {
+    pub fn from_metadata(metadata: &Metadata) -> Option<Validators> {
+        let last_modified = metadata.modified().ok()?;
+        let since_epoch = last_modified.duration_since(UNIX_EPOCH).ok()?;
+
+        let etag = format!(
+            "\"{:x}.{:x}-{:x}\"",
+            since_epoch.as_secs(),
+            since_epoch.subsec_nanos(),
+            metadata.len()
+        );
+
+        Some(Validators {
+            etag,
+            last_modified,
+        })
+    }
+
+    pub fn last_modified_header(&self) -> String {
+        httpdate::fmt_http_date(self.last_modified)
+    }
+
+    /// Whether the client's cached copy is still fresh, based on the
+    /// `If-None-Match` and `If-Modified-Since` request headers.
+    pub fn is_not_modified(&self, request: &Req) -> bool {
+        if let Some(if_none_match) = header_str(request, "If-None-Match") {
+            return if_none_match.trim() == "*"
+                || if_none_match
+                    .split(',')
+                    .any(|etag| weak_compare(etag.trim(), &self.etag));
+        }
+
+        match header_str(request, "If-Modified-Since").and_then(parse_http_date) {
+            Some(since) => HttpDate::from(self.last_modified) <= since,
+            None => false,
+        }
+    }
+}
+
+/// Turns a strong ETag into a weak one, eg. when the body is compressed
+/// on the fly and thus no longer byte-for-byte identical.
+pub fn weaken(etag: &str) -> String {
+    if etag.starts_with("W/") {
+        etag.to_string()
+    } else {
+        format!("W/{etag}")
+    }
+}
+
+pub fn weak_compare(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+fn parse_http_date(date: &str) -> Option<HttpDate> {
+    date.trim().parse().ok()
+}
+
+fn header_str<'a>(request: &'a Req, name: &str) -> Option<&'a str> {
+    request.headers().get(name)?.to_str().ok()
+}
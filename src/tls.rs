@@ -0,0 +1,119 @@
+use crate::body::{self, Body};
+use crate::config::TlsConfig;
+use crate::reload;
+
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use hyper::{Request, Response};
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+type Resp = Response<Body>;
+type Req = Request<hyper::body::Incoming>;
+
+/// Hands out the current certificate, which is swapped out whenever the
+/// certificate or key file changes on disk.
+#[derive(Debug)]
+struct ReloadingCertResolver {
+    certified_key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.certified_key.read().unwrap().clone())
+    }
+}
+
+pub fn build_acceptor(config: &TlsConfig, http2_enabled: bool) -> Result<TlsAcceptor, String> {
+    let certified_key = load_certified_key(&config.cert, &config.key)?;
+    let resolver = Arc::new(ReloadingCertResolver {
+        certified_key: RwLock::new(Arc::new(certified_key)),
+    });
+
+    watch_certificate(config, resolver.clone());
+
+    let mut server_config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|error| format!("Couldn't set up TLS: {error}"))?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+
+    server_config.alpn_protocols = if http2_enabled {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    } else {
+        vec![b"http/1.1".to_vec()]
+    };
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+fn watch_certificate(config: &TlsConfig, resolver: Arc<ReloadingCertResolver>) {
+    let cert = config.cert.clone();
+    let key = config.key.clone();
+
+    reload::watch_files(vec![cert.clone(), key.clone()], move || {
+        let certified_key = load_certified_key(&cert, &key)?;
+        *resolver.certified_key.write().unwrap() = Arc::new(certified_key);
+
+        println!("Reloaded TLS certificate {}", cert.display());
+        Ok(())
+    });
+}
+
+fn load_certified_key(cert: &Path, key: &Path) -> Result<CertifiedKey, String> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|error| format!("Couldn't read TLS certificate {}: {error}", cert.display()))?;
+
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}.", cert.display()));
+    }
+
+    let private_key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|error| format!("Couldn't read TLS key {}: {error}", key.display()))?;
+
+    CertifiedKey::from_der(certs, private_key, &ring::default_provider())
+        .map_err(|error| format!("Invalid TLS certificate or key: {error}"))
+}
+
+/// Sends plain HTTP visitors to the same URL on the HTTPS listener.
+pub fn redirect_to_https(request: &Req, config: &TlsConfig) -> Resp {
+    let host = request
+        .headers()
+        .get("Host")
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| request.uri().host())
+        .unwrap_or("localhost");
+
+    let port = config.address.rsplit(':').next().unwrap_or("443");
+    let authority = match port {
+        "443" => strip_port(host).to_string(),
+        port => format!("{}:{port}", strip_port(host)),
+    };
+
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+
+    Response::builder()
+        .status(308)
+        .header("Location", format!("https://{authority}{path}"))
+        .body(body::empty())
+        .unwrap()
+}
+
+fn strip_port(host: &str) -> &str {
+    // Careful not to break IPv6 literals like `[::1]`.
+    match host.rfind(':') {
+        Some(index) if !host[index..].contains(']') => &host[..index],
+        _ => host,
+    }
+}
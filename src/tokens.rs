@@ -0,0 +1,218 @@
+use crate::config::{self, AuthConfig, PublishMethod};
+use crate::permissions::{self, PathPattern};
+use crate::reload;
+
+use std::path::Path;
+use std::sync::{Arc, OnceLock, RwLock};
+
+use chrono::{DateTime, Utc};
+use hyper::Method;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+static TOKENS: OnceLock<RwLock<Vec<Arc<Token>>>> = OnceLock::new();
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct TokensFile {
+    #[serde(default)]
+    tokens: Vec<TokenConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct TokenConfig {
+    name: String,
+
+    /// Hex-encoded SHA-256 hash of the token, so the file itself
+    /// doesn't contain any secrets.
+    sha256: String,
+
+    /// Path prefixes or globs the token may publish to
+    paths: Vec<String>,
+
+    #[serde(default = "config::default_rule_methods")]
+    methods: Vec<PublishMethod>,
+
+    /// RFC 3339 timestamp, eg. `2027-01-01T00:00:00Z`
+    expires: Option<DateTime<Utc>>,
+}
+
+/// An API token, eg. for publishing from CI.
+pub struct Token {
+    pub name: String,
+    sha256: String,
+    paths: Vec<PathPattern>,
+    methods: Vec<Method>,
+    expires: Option<DateTime<Utc>>,
+}
+
+pub fn init(config: &AuthConfig) -> Result<(), String> {
+    let path = match &config.tokens_file {
+        Some(path) => path.clone(),
+        None => return Ok(()),
+    };
+
+    let tokens = load_tokens_file(&path)?;
+
+    if TOKENS.set(RwLock::new(tokens)).is_err() {
+        return Err(String::from("Tokens should only be initialized once!"));
+    }
+
+    reload::watch_files(vec![path.clone()], move || {
+        let tokens = load_tokens_file(&path)?;
+        let count = tokens.len();
+
+        *TOKENS.get().unwrap().write().unwrap() = tokens;
+
+        println!("Reloaded {count} token(s) from {}", path.display());
+        Ok(())
+    });
+
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    TOKENS.get().is_some()
+}
+
+/// Looks up the token, comparing hashes in constant time. The error
+/// explains why the token was rejected.
+pub fn authenticate(token: &str) -> Result<Arc<Token>, String> {
+    let tokens = match TOKENS.get() {
+        Some(tokens) => tokens.read().unwrap(),
+        None => return Err(String::from("Bearer tokens aren't enabled on this server.")),
+    };
+
+    let hash = sha256_hex(token);
+    let mut found = None;
+
+    // Check every token, so the time taken doesn't reveal which one matched.
+    for candidate in tokens.iter() {
+        if bool::from(candidate.sha256.as_bytes().ct_eq(hash.as_bytes())) {
+            found = Some(candidate.clone());
+        }
+    }
+
+    match found {
+        Some(token) if token.is_expired() => Err(format!("Token `{}` has expired.", token.name)),
+        Some(token) => Ok(token),
+        None => Err(String::from("Invalid token.")),
+    }
+}
+
+impl Token {
+    fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= Utc::now())
+    }
+
+    /// Checks the request against the scope of the token.
+    pub fn authorize(&self, method: &Method, path: &str) -> Result<(), String> {
+        let path = permissions::normalize_request_path(path);
+
+        if !self.methods.contains(method) {
+            return Err(format!(
+                "Forbidden; token `{}` isn't allowed to `{method}`.",
+                self.name
+            ));
+        }
+
+        if !self.paths.iter().any(|pattern| pattern.matches(&path)) {
+            return Err(format!(
+                "Forbidden; token `{}` isn't allowed to publish to {path}.",
+                self.name
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+fn load_tokens_file(path: &Path) -> Result<Vec<Arc<Token>>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|error| format!("Couldn't read tokens file {}: {error}", path.display()))?;
+
+    let file: TokensFile = toml::from_str(&contents)
+        .map_err(|error| format!("Invalid tokens file {}: {error}", path.display()))?;
+
+    file.tokens
+        .into_iter()
+        .map(|token| {
+            let invalid_token =
+                |reason: String| format!("Invalid token `{}` in {}: {reason}", token.name, path.display());
+
+            let sha256 = token.sha256.to_lowercase();
+
+            if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(invalid_token(String::from("`sha256` should be 64 hex characters.")));
+            }
+
+            let paths = token
+                .paths
+                .iter()
+                .map(|path| permissions::compile_path_pattern(path))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(invalid_token)?;
+
+            Ok(Arc::new(Token {
+                name: token.name.clone(),
+                sha256,
+                paths,
+                methods: token.methods.iter().map(|method| method.to_method()).collect(),
+                expires: token.expires,
+            }))
+        })
+        .collect()
+}
+
+fn sha256_hex(value: &str) -> String {
+    Sha256::digest(value.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(paths: &[&str]) -> Token {
+        Token {
+            name: String::from("ci"),
+            sha256: String::new(),
+            paths: paths
+                .iter()
+                .map(|path| permissions::compile_path_pattern(path).unwrap())
+                .collect(),
+            methods: vec![Method::PUT],
+            expires: None,
+        }
+    }
+
+    #[test]
+    fn scope_prefixes_match_whole_segments() {
+        let token = token(&["/blog"]);
+
+        assert!(token.authorize(&Method::PUT, "/blog").is_ok());
+        assert!(token.authorize(&Method::PUT, "/blog/post.htmd").is_ok());
+        assert!(token.authorize(&Method::PUT, "/blog-old/post.htmd").is_err());
+        assert!(token.authorize(&Method::PUT, "/blogX").is_err());
+    }
+
+    #[test]
+    fn scope_is_checked_after_normalizing() {
+        let token = token(&["/blog/"]);
+
+        assert!(token.authorize(&Method::PUT, "/blog/../admin/x").is_err());
+        assert!(token.authorize(&Method::PUT, "/blog/%2e%2e/admin/x").is_err());
+        assert!(token.authorize(&Method::PUT, "/blog/./a/../post.htmd").is_ok());
+    }
+
+    #[test]
+    fn scope_limits_methods() {
+        let token = token(&["/blog/"]);
+
+        assert!(token.authorize(&Method::DELETE, "/blog/post.htmd").is_err());
+    }
+}
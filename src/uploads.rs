@@ -1,58 +1,152 @@
+use crate::body::Body;
+use crate::config;
 use crate::files;
 use crate::response;
 
 use std::convert::Infallible;
+use std::path::PathBuf;
 
-use http_body_util::{BodyExt, Full};
-use hyper::body::Bytes;
-use hyper::Uri;
+use http_body_util::BodyExt;
+use hyper::body::Incoming;
 use hyper::{Request, Response};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
 
-type Resp = Response<Full<Bytes>>;
+type Resp = Response<Body>;
 type Req = Request<hyper::body::Incoming>;
 
+enum UploadError {
+    TooLarge,
+    InvalidBody,
+    Internal(String),
+}
+
 pub async fn process_put_request(request: Req) -> Result<Resp, Infallible> {
-    let uri = request.uri().to_owned();
+    let path_buffer = match files::get_path_buffer_for_new_path(request.uri().path()) {
+        Some(path_buffer) => path_buffer,
+        None => {
+            return Ok(response::serve(
+                403,
+                "Forbidden; you cannot upload outside of scope.",
+            ))
+        }
+    };
+
+    // Only files can be uploaded. Without this, uploading to the scope
+    // itself would put the temporary file outside of it.
+    if request.uri().path().ends_with('/') || path_buffer.is_dir() {
+        return Ok(response::serve(
+            409,
+            "Conflict; you cannot upload to a directory.",
+        ));
+    }
+
+    if exceeds_max_upload_size(&request) {
+        return Ok(serve_too_large());
+    }
 
-    match read_body(request).await {
-        Some(bytes) => handle_upload(&uri, bytes).await,
-        None => Ok(response::serve(
+    let path = path_buffer.to_str().unwrap();
+
+    match upload_file(path, request.into_body()).await {
+        Ok(()) => Ok(response::serve(201, "")),
+        Err(UploadError::TooLarge) => Ok(serve_too_large()),
+        Err(UploadError::InvalidBody) => Ok(response::serve(
             400,
-            "Bad request; empty body, nothing to upload.",
+            "Bad request; couldn't read the request body.",
         )),
+        Err(UploadError::Internal(error)) => {
+            // The error contains the path on disk, which the client
+            // doesn't need to know.
+            eprintln!("Upload to {path} failed: {error}");
+
+            Ok(response::serve(
+                500,
+                "Internal server error; couldn't store the upload.",
+            ))
+        }
     }
 }
 
-async fn read_body(request: Req) -> Option<Bytes> {
-    let body = request.into_body();
-    let collected_body = body.collect().await.ok()?;
+/// Checks the `Content-Length` header up front, so we don't have to
+/// wait for the body to reject obviously oversized uploads.
+fn exceeds_max_upload_size(request: &Req) -> bool {
+    request
+        .headers()
+        .get("Content-Length")
+        .and_then(|header| header.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok())
+        .is_some_and(|length| length > config::get().uploads.max_size)
+}
 
-    Some(collected_body.to_bytes())
+fn serve_too_large() -> Resp {
+    let max_size = config::get().uploads.max_size;
+
+    response::serve(
+        413,
+        &format!("Payload too large; uploads can be at most {max_size} bytes."),
+    )
 }
 
-async fn handle_upload(uri: &Uri, bytes: Bytes) -> Result<Resp, Infallible> {
-    let path = &files::uri_to_local_path(uri);
+/// Streams the body to a temporary file, which replaces the target file
+/// once it's complete. That way, readers never see a half-written file.
+async fn upload_file(path: &str, body: Incoming) -> Result<(), UploadError> {
+    files::create_parent_directories(path)
+        .await
+        .map_err(UploadError::Internal)?;
+
+    let (temp_path, mut file) = files::create_temp_file(path)
+        .await
+        .map_err(UploadError::Internal)?;
+
+    let mut temp_file = TempFileGuard {
+        path: temp_path,
+        persisted: false,
+    };
 
-    match files::get_path_buffer_for_allowed_path(path) {
-        Some(path_buffer) => {
-            let path = path_buffer.to_str().unwrap();
+    write_body(&mut file, body).await?;
 
-            match upload_file(path, bytes).await {
-                Ok(response) => Ok(response),
-                Err(error) => Ok(response::serve(
-                    500,
-                    &format!("Internal server error; {error}"),
-                )),
+    files::persist_temp_file(&temp_file.path, file, path)
+        .await
+        .map_err(UploadError::Internal)?;
+
+    temp_file.persisted = true;
+    Ok(())
+}
+
+async fn write_body(file: &mut File, mut body: Incoming) -> Result<(), UploadError> {
+    let max_size = config::get().uploads.max_size;
+    let mut written: u64 = 0;
+
+    while let Some(frame) = body.frame().await {
+        let frame = frame.map_err(|_| UploadError::InvalidBody)?;
+
+        if let Ok(data) = frame.into_data() {
+            written += data.len() as u64;
+
+            if written > max_size {
+                return Err(UploadError::TooLarge);
             }
+
+            file.write_all(&data).await.map_err(|_| {
+                UploadError::Internal(String::from("couldn't write the uploaded file."))
+            })?;
         }
-        None => Ok(response::serve(
-            403,
-            "Forbidden; you cannot upload outside of scope.",
-        )),
     }
+
+    Ok(())
+}
+
+/// Removes the temporary file unless it was renamed into place, even
+/// when the upload is aborted halfway (eg. when shutting down).
+struct TempFileGuard {
+    path: PathBuf,
+    persisted: bool,
 }
 
-async fn upload_file(path: &str, bytes: Bytes) -> Result<Resp, String> {
-    files::write_file(path, bytes).await?;
-    Ok(response::serve(201, ""))
+impl Drop for TempFileGuard {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
 }